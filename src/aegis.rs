@@ -0,0 +1,379 @@
+use std::collections::BTreeMap;
+use base64;
+use crypto::aead::{AeadDecryptor, AeadEncryptor};
+use crypto::aes::{KeySize};
+use crypto::aes_gcm::{AesGcm};
+use crypto::scrypt::{scrypt, ScryptParams};
+use rand::{OsRng, Rng};
+use rustc_serialize::hex::{FromHex, ToHex};
+use rustc_serialize::json::{self, Json};
+use errors::{Error};
+use totpkeep::{Algorithm, OtpKind, OtpParams};
+use utils::*;
+
+const HEADER_VERSION: u64 = 1;
+const DB_VERSION: u64 = 2;
+const SLOT_PASSWORD: u64 = 1;
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const SALT_LEN: usize = 32;
+// the parameters Aegis itself uses for password slots
+const SCRYPT_LOG_N: u8 = 15;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+// Slots asking for more than four times the memory of those Aegis writes, 32 MiB,
+// are treated as corruption. Single parameters are limited first, so the budget doesn't overflow.
+const MAX_SCRYPT_LOG_N: u8 = 17;
+const MAX_SCRYPT_R: u64 = 16;
+const MAX_SCRYPT_P: u64 = 4;
+const MAX_SCRYPT_MEMORY: u64 = 4 * 128 * SCRYPT_R as u64 * (1 << SCRYPT_LOG_N) * SCRYPT_P as u64;
+
+/// TOTP or HOTP entry of Aegis vault. Steam, mOTP and Yandex entries are not supported.
+#[derive(Debug)]
+pub struct AegisEntry {
+    pub name: String,
+    pub issuer: Option<String>,
+    pub secret: Vec<u8>,
+    pub params: OtpParams,
+    pub kind: OtpKind
+}
+
+fn field<'a>(object: &'a Json, name: &str) -> Result<&'a Json, Error> {
+    object.find(name).ok_or(Error::WrongAegisFile)
+}
+
+fn string_field<'a>(object: &'a Json, name: &str) -> Result<&'a str, Error> {
+    field(object, name)?.as_string().ok_or(Error::WrongAegisFile)
+}
+
+fn u64_field(object: &Json, name: &str) -> Result<u64, Error> {
+    field(object, name)?.as_u64().ok_or(Error::WrongAegisFile)
+}
+
+fn hex_field(object: &Json, name: &str) -> Result<Vec<u8>, Error> {
+    string_field(object, name)?.from_hex().map_err(|_| Error::WrongAegisFile)
+}
+
+fn decrypt_gcm(key: &[u8], params: &Json, ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+    let nonce = hex_field(params, "nonce")?;
+    let tag = hex_field(params, "tag")?;
+    if key.len() != KEY_LEN || nonce.len() != NONCE_LEN || tag.len() != TAG_LEN {
+        return Err(Error::WrongAegisFile)
+    }
+    let mut output = vec![0u8; ciphertext.len()];
+    if !AesGcm::new(KeySize::KeySize256, key, &nonce, &[]).decrypt(ciphertext, &mut output, &tag) {
+        return Err(Error::WrongPassword)
+    }
+    Ok(output)
+}
+
+/// Returns ciphertext and `{"nonce": ..., "tag": ...}` parameters.
+fn encrypt_gcm(key: &[u8], plaintext: &[u8]) -> Result<(Vec<u8>, Json), Error> {
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng::new()?.fill_bytes(&mut nonce);
+    let mut output = vec![0u8; plaintext.len()];
+    let mut tag = [0u8; TAG_LEN];
+    AesGcm::new(KeySize::KeySize256, key, &nonce, &[]).encrypt(plaintext, &mut output, &mut tag);
+    let mut params = BTreeMap::new();
+    params.insert("nonce".to_string(), Json::String(nonce.to_hex()));
+    params.insert("tag".to_string(), Json::String(tag.to_hex()));
+    Ok((output, Json::Object(params)))
+}
+
+/// Bytes scrypt works with, 128 * r for each of n blocks for each of p lanes.
+fn scrypt_memory(n: u64, r: u64, p: u64) -> u64 {
+    128 * r * n * p
+}
+
+fn slot_key(password: &str, slot: &Json) -> Result<Vec<u8>, Error> {
+    let n = u64_field(slot, "n")?;
+    let r = u64_field(slot, "r")?;
+    let p = u64_field(slot, "p")?;
+    if !n.is_power_of_two() || n < 2 || r == 0 || r > MAX_SCRYPT_R || p == 0 || p > MAX_SCRYPT_P {
+        return Err(Error::WrongAegisFile)
+    }
+    let log_n = n.trailing_zeros() as u8;
+    if log_n > MAX_SCRYPT_LOG_N || scrypt_memory(n, r, p) > MAX_SCRYPT_MEMORY {
+        return Err(Error::WrongAegisFile)
+    }
+    let mut key = vec![0u8; KEY_LEN];
+    scrypt(password.as_bytes(), &hex_field(slot, "salt")?, &ScryptParams::new(log_n, r as u32, p as u32), &mut key);
+    Ok(key)
+}
+
+/// Master key from the first password slot the password opens.
+fn master_key(password: &str, slots: &[Json]) -> Result<Vec<u8>, Error> {
+    for slot in slots.iter().filter(|s| s.find("type").and_then(|t| t.as_u64()) == Some(SLOT_PASSWORD)) {
+        let mut key = slot_key(password, slot)?;
+        let opened = decrypt_gcm(&key, field(slot, "key_params")?, &hex_field(slot, "key")?);
+        zero(&mut key[..]);
+        if let Ok(master) = opened {
+            return Ok(master)
+        }
+    }
+    Err(Error::WrongPassword)
+}
+
+fn parse_entry(entry: &Json) -> Result<Option<AegisEntry>, Error> {
+    let info = field(entry, "info")?;
+    let hotp = match string_field(entry, "type")? {
+        "totp" => false,
+        "hotp" => true,
+        _ => return Ok(None)
+    };
+    let mut params = OtpParams::default();
+    if let Some(algo) = info.find("algo").and_then(|a| a.as_string()) {
+        params.algorithm = Algorithm::parse(algo)?;
+    }
+    if let Some(digits) = info.find("digits").and_then(|d| d.as_u64()) {
+        params.digits = digits as u32;
+    }
+    if !hotp {
+        if let Some(period) = info.find("period").and_then(|p| p.as_u64()) {
+            params.period = period;
+        }
+    }
+    params.validate()?;
+    let secret = {
+        use base32::{decode, Alphabet};
+        let encoded = string_field(info, "secret")?.trim().to_uppercase().replace("=", "");
+        decode(Alphabet::RFC4648 { padding: false }, &encoded).ok_or(Error::WrongServiceRecordData)?
+    };
+    let issuer = entry.find("issuer").and_then(|i| i.as_string()).unwrap_or("");
+    Ok(Some(AegisEntry {
+        name: string_field(entry, "name")?.to_string(),
+        issuer: if issuer.is_empty() { None } else { Some(issuer.to_string()) },
+        secret,
+        params,
+        kind: match hotp {
+            true => OtpKind::Hotp { counter: info.find("counter").and_then(|c| c.as_u64()).unwrap_or(0) },
+            false => OtpKind::Totp
+        }
+    }))
+}
+
+/// Reads plain or encrypted Aegis vault export. `password` is called only for encrypted one.
+/// Returns supported entries and names of the skipped ones.
+pub fn parse<F>(content: &[u8], password: F) -> Result<(Vec<AegisEntry>, Vec<String>), Error>
+    where F: FnOnce() -> Result<String, Error> {
+    let text = ::std::str::from_utf8(content).map_err(|_| Error::WrongAegisFile)?;
+    let vault = Json::from_str(text).map_err(|_| Error::WrongAegisFile)?;
+    let header = field(&vault, "header")?;
+    let db = match field(header, "slots")?.as_array() {
+        None => field(&vault, "db")?.clone(),
+        Some(slots) => {
+            let ciphertext = base64::decode(string_field(&vault, "db")?).map_err(|_| Error::WrongAegisFile)?;
+            let mut key = master_key(&password()?, slots)?;
+            let plaintext = decrypt_gcm(&key, field(header, "params")?, &ciphertext);
+            zero(&mut key[..]);
+            let mut plaintext = plaintext?;
+            let db = ::std::str::from_utf8(&plaintext).ok().and_then(|t| Json::from_str(t).ok());
+            zero(&mut plaintext[..]);
+            db.ok_or(Error::WrongAegisFile)?
+        }
+    };
+    let mut entries = Vec::new();
+    let mut skipped = Vec::new();
+    for entry in field(&db, "entries")?.as_array().ok_or(Error::WrongAegisFile)? {
+        match parse_entry(entry)? {
+            Some(parsed) => entries.push(parsed),
+            None => skipped.push(entry.find("name").and_then(|n| n.as_string()).unwrap_or("").to_string())
+        }
+    }
+    Ok((entries, skipped))
+}
+
+fn object(pairs: Vec<(&str, Json)>) -> Json {
+    Json::Object(pairs.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+}
+
+/// Random version 4 UUID, Aegis identifies entries and slots by them.
+fn uuid(gen: &mut OsRng) -> String {
+    let mut bytes = [0u8; 16];
+    gen.fill_bytes(&mut bytes);
+    bytes[6] = bytes[6] & 0x0f | 0x40;
+    bytes[8] = bytes[8] & 0x3f | 0x80;
+    let hex = bytes.to_hex();
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+fn write_entry(entry: &AegisEntry, gen: &mut OsRng) -> Json {
+    use base32::{encode, Alphabet};
+    let mut info = vec![
+        ("secret", Json::String(encode(Alphabet::RFC4648 { padding: false }, &entry.secret))),
+        ("algo", Json::String(entry.params.algorithm.name().to_string())),
+        ("digits", Json::U64(entry.params.digits as u64))
+    ];
+    let kind = match entry.kind {
+        OtpKind::Totp => {
+            info.push(("period", Json::U64(entry.params.period)));
+            "totp"
+        },
+        OtpKind::Hotp { counter } => {
+            info.push(("counter", Json::U64(counter)));
+            "hotp"
+        }
+    };
+    object(vec![
+        ("type", Json::String(kind.to_string())),
+        ("uuid", Json::String(uuid(gen))),
+        ("name", Json::String(entry.name.clone())),
+        ("issuer", Json::String(entry.issuer.clone().unwrap_or_default())),
+        ("note", Json::String(String::new())),
+        ("icon", Json::Null),
+        ("info", object(info))
+    ])
+}
+
+/// Aegis vault JSON with the entries. With `password` the database is encrypted
+/// with a random master key stored in a single password slot, as Aegis does itself.
+pub fn write(entries: &[AegisEntry], password: Option<&str>) -> Result<Vec<u8>, Error> {
+    let mut gen = OsRng::new()?;
+    let db = object(vec![
+        ("version", Json::U64(DB_VERSION)),
+        ("entries", Json::Array(entries.iter().map(|e| write_entry(e, &mut gen)).collect()))
+    ]);
+    let (slots, params, db) = match password {
+        None => (Json::Null, Json::Null, db),
+        Some(password) => {
+            let mut master = vec![0u8; KEY_LEN];
+            gen.fill_bytes(&mut master);
+            let mut salt = [0u8; SALT_LEN];
+            gen.fill_bytes(&mut salt);
+            let mut key = vec![0u8; KEY_LEN];
+            scrypt(password.as_bytes(), &salt, &ScryptParams::new(SCRYPT_LOG_N, SCRYPT_R, SCRYPT_P), &mut key);
+            let (encrypted_master, key_params) = encrypt_gcm(&key, &master)?;
+            zero(&mut key[..]);
+            let slot = object(vec![
+                ("type", Json::U64(SLOT_PASSWORD)),
+                ("uuid", Json::String(uuid(&mut gen))),
+                ("key", Json::String(encrypted_master.to_hex())),
+                ("key_params", key_params),
+                ("n", Json::U64(1 << SCRYPT_LOG_N)),
+                ("r", Json::U64(SCRYPT_R as u64)),
+                ("p", Json::U64(SCRYPT_P as u64)),
+                ("salt", Json::String(salt.to_hex())),
+                ("repaired", Json::Boolean(true))
+            ]);
+            let mut plaintext = db.to_string().into_bytes();
+            let encrypted = encrypt_gcm(&master, &plaintext);
+            zero(&mut plaintext[..]);
+            zero(&mut master[..]);
+            let (ciphertext, params) = encrypted?;
+            (Json::Array(vec![slot]), params, Json::String(base64::encode(&ciphertext)))
+        }
+    };
+    let vault = object(vec![
+        ("version", Json::U64(HEADER_VERSION)),
+        ("header", object(vec![("slots", slots), ("params", params)])),
+        ("db", db)
+    ]);
+    Ok(format!("{}\n", json::as_pretty_json(&vault)).into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PASSWORD: &str = "backup password";
+
+    fn entries() -> Vec<AegisEntry> {
+        vec![
+            AegisEntry {
+                name: String::from("alice@example.com"),
+                issuer: Some(String::from("Example")),
+                secret: b"Hello!\xde\xad\xbe\xef".to_vec(),
+                params: OtpParams { digits: 8, period: 60, algorithm: Algorithm::SHA256, epoch: 0 },
+                kind: OtpKind::Totp
+            },
+            AegisEntry {
+                name: String::from("key"),
+                issuer: None,
+                secret: b"12345678901234567890".to_vec(),
+                params: OtpParams::default(),
+                kind: OtpKind::Hotp { counter: 7 }
+            }
+        ]
+    }
+
+    fn assert_entries(parsed: &[AegisEntry]) {
+        let expected = entries();
+        assert_eq!(parsed.len(), expected.len());
+        for (parsed, expected) in parsed.iter().zip(expected.iter()) {
+            assert_eq!(parsed.name, expected.name);
+            assert_eq!(parsed.issuer, expected.issuer);
+            assert_eq!(parsed.secret, expected.secret);
+            assert_eq!(parsed.params, expected.params);
+            assert_eq!(parsed.kind, expected.kind);
+        }
+    }
+
+    fn no_password() -> Result<String, Error> {
+        panic!("password asked for a plain vault")
+    }
+
+    /// Vault with the single slot changed by `change`.
+    fn with_slot<F: FnOnce(&mut BTreeMap<String, Json>)>(vault: &[u8], change: F) -> Vec<u8> {
+        let mut vault = Json::from_str(::std::str::from_utf8(vault).unwrap()).unwrap();
+        {
+            let slots = vault.as_object_mut().unwrap().get_mut("header").unwrap()
+                .as_object_mut().unwrap().get_mut("slots").unwrap().as_array_mut().unwrap();
+            change(slots[0].as_object_mut().unwrap());
+        }
+        vault.to_string().into_bytes()
+    }
+
+    #[test]
+    fn plain_round_trip() {
+        let (parsed, skipped) = parse(&write(&entries(), None).unwrap(), no_password).unwrap();
+        assert_entries(&parsed);
+        assert!(skipped.is_empty());
+    }
+
+    #[test]
+    fn encrypted_round_trip() {
+        let vault = write(&entries(), Some(PASSWORD)).unwrap();
+        assert!(!String::from_utf8(vault.clone()).unwrap().contains("alice"));
+        let (parsed, _) = parse(&vault, || Ok(PASSWORD.to_string())).unwrap();
+        assert_entries(&parsed);
+        match parse(&vault, || Ok(String::from("wrong"))) {
+            Err(Error::WrongPassword) => (),
+            other => panic!("expected WrongPassword, got {:?}", other.map(|(entries, _)| entries))
+        }
+    }
+
+    #[test]
+    fn unsupported_entries_are_skipped() {
+        let vault = br#"{"version": 1, "header": {"slots": null, "params": null}, "db": {"version": 2, "entries": [
+            {"type": "steam", "name": "game", "info": {"secret": "JBSWY3DP"}},
+            {"type": "totp", "name": "site", "issuer": "", "info": {"secret": "jbswy3dp====", "digits": 6}}
+        ]}}"#;
+        let (parsed, skipped) = parse(vault, no_password).unwrap();
+        assert_eq!(parsed.len(), 1);
+        assert_eq!(parsed[0].name, "site");
+        assert_eq!(parsed[0].issuer, None);
+        assert_eq!(parsed[0].secret, b"Hello".to_vec());
+        assert_eq!(skipped, vec![String::from("game")]);
+    }
+
+    #[test]
+    fn expensive_scrypt_is_refused() {
+        let vault = write(&entries(), Some(PASSWORD)).unwrap();
+        let changes: Vec<&[(&str, u64)]> = vec![
+            &[("n", 1 << (MAX_SCRYPT_LOG_N + 1))], &[("n", 3)], &[("r", 0)], &[("r", MAX_SCRYPT_R + 1)],
+            &[("p", MAX_SCRYPT_P + 1)],
+            // each within its limit, together over the budget
+            &[("n", 1 << MAX_SCRYPT_LOG_N), ("r", MAX_SCRYPT_R)]
+        ];
+        for change in changes {
+            let tampered = with_slot(&vault, |slot| for &(name, value) in change {
+                slot.insert(name.to_string(), Json::U64(value));
+            });
+            match parse(&tampered, || Ok(PASSWORD.to_string())) {
+                Err(Error::WrongAegisFile) => (),
+                _ => panic!("slot with {:?} was accepted", change)
+            }
+        }
+    }
+}
@@ -0,0 +1,288 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, MetadataExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use byteorder::{ByteOrder, BigEndian};
+use libc;
+use crpt::{FileKey};
+use errors::{Error};
+use utils::*;
+
+/// Environment variable with path to the agent socket, like `SSH_AUTH_SOCK` for ssh-agent.
+pub const AGENT_SOCK_ENV: &str = "TOTPKEEP_AGENT_SOCK";
+
+// Every message is u8 tag, big endian u32 payload length and payload.
+const GET_KEY: u8 = 1;      // payload: key context
+const ADD_KEY: u8 = 2;      // payload: u32 context length, context, key
+const LOCK: u8 = 3;         // no payload, forget all keys
+const OK: u8 = 128;         // payload: key for GET_KEY, empty otherwise
+const NOT_FOUND: u8 = 129;
+const FAILURE: u8 = 130;
+
+const MAX_PAYLOAD: usize = 4096;
+const IO_TIMEOUT_SECS: u64 = 5;
+
+/// Key bytes pinned in memory so they are never swapped out, wiped on drop.
+struct LockedKey(Vec<u8>);
+
+impl LockedKey {
+    fn new(key: &[u8]) -> Self {
+        let locked = LockedKey(key.to_vec());
+        unsafe { libc::mlock(locked.0.as_ptr() as *const libc::c_void, locked.0.len()); }
+        locked
+    }
+}
+
+impl Drop for LockedKey {
+    fn drop(&mut self) {
+        zero(&mut self.0[..]);
+        unsafe { libc::munlock(self.0.as_ptr() as *const libc::c_void, self.0.len()); }
+    }
+}
+
+struct KeyStore {
+    keys: HashMap<Vec<u8>, LockedKey>,
+    last_used: Instant
+}
+
+fn write_message(stream: &mut UnixStream, tag: u8, payload: &[u8]) -> Result<(), Error> {
+    let mut header = [0u8; 5];
+    header[0] = tag;
+    BigEndian::write_u32(&mut header[1..], payload.len() as u32);
+    stream.write_all(&header)?;
+    stream.write_all(payload)?;
+    Ok(())
+}
+
+fn read_message(stream: &mut UnixStream) -> Result<(u8, Vec<u8>), Error> {
+    let mut header = [0u8; 5];
+    stream.read_exact(&mut header)?;
+    let len = BigEndian::read_u32(&header[1..]) as usize;
+    if len > MAX_PAYLOAD {
+        return Err(Error::AgentFailure)
+    }
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload[..])?;
+    Ok((header[0], payload))
+}
+
+/// User of the process at the other end of the socket.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn peer_uid(stream: &UnixStream) -> Option<libc::uid_t> {
+    let mut cred: libc::ucred = unsafe { ::std::mem::zeroed() };
+    let mut len = ::std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEERCRED,
+                         &mut cred as *mut libc::ucred as *mut libc::c_void, &mut len)
+    };
+    match result {
+        0 => Some(cred.uid),
+        _ => None
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn peer_uid(stream: &UnixStream) -> Option<libc::uid_t> {
+    let mut uid: libc::uid_t = 0;
+    let mut gid: libc::gid_t = 0;
+    match unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } {
+        0 => Some(uid),
+        _ => None
+    }
+}
+
+/// Keys go only to and from processes of the same user.
+fn check_peer(stream: &UnixStream) -> Result<(), Error> {
+    match peer_uid(stream) {
+        Some(uid) if uid == unsafe { libc::getuid() } => Ok(()),
+        _ => Err(Error::AgentNotTrusted)
+    }
+}
+
+fn connect() -> Result<UnixStream, Error> {
+    let path = env::var_os(AGENT_SOCK_ENV).ok_or(Error::AgentNotRunning)?;
+    let stream = UnixStream::connect(path).map_err(|_| Error::AgentNotRunning)?;
+    check_peer(&stream)?;
+    stream.set_read_timeout(Some(Duration::from_secs(IO_TIMEOUT_SECS)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(IO_TIMEOUT_SECS)))?;
+    Ok(stream)
+}
+
+fn request(tag: u8, payload: &[u8]) -> Result<(u8, Vec<u8>), Error> {
+    let mut stream = connect()?;
+    write_message(&mut stream, tag, payload)?;
+    read_message(&mut stream)
+}
+
+/// Key for the given context if the agent is configured, running and holds it.
+pub fn cached_key(context: &[u8]) -> Option<FileKey> {
+    match request(GET_KEY, context) {
+        Ok((OK, mut key)) => {
+            let result = FileKey::from_context(context, &key[..]).ok();
+            zero(&mut key[..]);
+            result
+        },
+        _ => None
+    }
+}
+
+pub fn add_key(key: &FileKey) -> Result<(), Error> {
+    let context = key.context();
+    let mut payload = vec![0u8; 4];
+    BigEndian::write_u32(&mut payload[..], context.len() as u32);
+    payload.extend_from_slice(&context[..]);
+    payload.extend_from_slice(key.bytes());
+    let result = request(ADD_KEY, &payload[..]);
+    zero(&mut payload[..]);
+    match result? {
+        (OK, _) => Ok(()),
+        _ => Err(Error::AgentFailure)
+    }
+}
+
+pub fn lock() -> Result<(), Error> {
+    match request(LOCK, &[])? {
+        (OK, _) => Ok(()),
+        _ => Err(Error::AgentFailure)
+    }
+}
+
+/// `$XDG_RUNTIME_DIR/totpkeep-agent.sock` or `/tmp/totpkeep-<uid>/agent.sock`.
+fn default_socket_path() -> Result<PathBuf, Error> {
+    if let Some(dir) = env::var_os("XDG_RUNTIME_DIR") {
+        return Ok(PathBuf::from(dir).join("totpkeep-agent.sock"))
+    }
+    let dir = env::temp_dir().join(format!("totpkeep-{}", unsafe { libc::getuid() }));
+    if fs::symlink_metadata(&dir).is_err() {
+        fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    }
+    check_private_dir(&dir)?;
+    Ok(dir.join("agent.sock"))
+}
+
+/// The shared temporary directory lets anyone create the directory first. Whoever owns it
+/// can replace the socket, so it must be a real directory of this user closed to others.
+fn check_private_dir(dir: &Path) -> Result<(), Error> {
+    let meta = fs::symlink_metadata(dir)?;
+    if !meta.is_dir() || meta.uid() != unsafe { libc::getuid() } || meta.mode() & 0o777 != 0o700 {
+        return Err(Error::AgentNotTrusted)
+    }
+    Ok(())
+}
+
+fn bind(path: &Path) -> Result<UnixListener, Error> {
+    if let Ok(meta) = fs::symlink_metadata(path) {
+        // a mistyped --socket must not delete the file it names
+        if !meta.file_type().is_socket() {
+            return Err(Error::FileExists)
+        }
+        if UnixStream::connect(path).is_ok() {
+            return Err(Error::AgentAlreadyRunning)
+        }
+        // left behind by the agent that was killed
+        fs::remove_file(path)?;
+    }
+    // created accessible only to the user, there is no moment others could connect
+    let umask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(path);
+    unsafe { libc::umask(umask); }
+    Ok(listener?)
+}
+
+fn handle(store: &Mutex<KeyStore>, stream: &mut UnixStream) -> Result<(), Error> {
+    check_peer(stream)?;
+    stream.set_read_timeout(Some(Duration::from_secs(IO_TIMEOUT_SECS)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(IO_TIMEOUT_SECS)))?;
+    let (tag, mut payload) = read_message(stream)?;
+    let mut store = store.lock().unwrap();
+    store.last_used = Instant::now();
+    let result = match tag {
+        GET_KEY => match store.keys.get(&payload) {
+            Some(key) => write_message(stream, OK, &key.0[..]),
+            None => write_message(stream, NOT_FOUND, &[])
+        },
+        ADD_KEY if payload.len() >= 4 => {
+            let context_len = BigEndian::read_u32(&payload[..4]) as usize;
+            if payload.len() < 4 + context_len {
+                write_message(stream, FAILURE, &[])
+            } else {
+                let context = payload[4..4 + context_len].to_vec();
+                let key = LockedKey::new(&payload[4 + context_len..]);
+                store.keys.insert(context, key);
+                write_message(stream, OK, &[])
+            }
+        },
+        LOCK => {
+            store.keys.clear();
+            write_message(stream, OK, &[])
+        },
+        _ => write_message(stream, FAILURE, &[])
+    };
+    zero(&mut payload[..]);
+    result
+}
+
+fn serve(listener: UnixListener, timeout: u64) {
+    let store = Arc::new(Mutex::new(KeyStore { keys: HashMap::new(), last_used: Instant::now() }));
+    if timeout > 0 {
+        let store = store.clone();
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(1));
+            let mut store = store.lock().unwrap();
+            if store.last_used.elapsed() >= Duration::from_secs(timeout) {
+                store.keys.clear();
+            }
+        });
+    }
+    for stream in listener.incoming() {
+        if let Ok(mut stream) = stream {
+            let _ = handle(&store, &mut stream);
+        }
+    }
+}
+
+/// Detaches from the terminal. Returns false in the parent process.
+fn daemonize() -> Result<bool, Error> {
+    match unsafe { libc::fork() } {
+        -1 => return Err(Error::AgentFailure),
+        0 => (),
+        _ => return Ok(false)
+    }
+    let null = fs::OpenOptions::new().read(true).write(true).open("/dev/null")?;
+    unsafe {
+        libc::setsid();
+        libc::dup2(null.as_raw_fd(), 0);
+        libc::dup2(null.as_raw_fd(), 1);
+        libc::dup2(null.as_raw_fd(), 2);
+    }
+    Ok(true)
+}
+
+/// Runs the agent. Unless `foreground` is set the agent detaches after printing
+/// shell commands to set `TOTPKEEP_AGENT_SOCK`, so it can be started with `eval $(totpkeep agent)`.
+/// Keys are forgotten after `timeout` seconds without requests, 0 keeps them until `lock`.
+pub fn run(socket: Option<&str>, timeout: u64, foreground: bool) -> Result<(), Error> {
+    let path = match socket {
+        Some(path) => PathBuf::from(path),
+        None => default_socket_path()?
+    };
+    // keys must not end up in core dumps
+    let no_core = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    unsafe { libc::setrlimit(libc::RLIMIT_CORE, &no_core); }
+
+    let listener = bind(&path)?;
+    println!("{}={}; export {};", AGENT_SOCK_ENV, path.display(), AGENT_SOCK_ENV);
+    ::std::io::stdout().flush()?;
+    if !foreground && !daemonize()? {
+        return Ok(())
+    }
+    serve(listener, timeout);
+    Ok(())
+}
@@ -0,0 +1,175 @@
+use errors::{Error};
+use utils::{read_varint, write_varint};
+
+/// Decrypted body starts with these bytes followed by a version byte. 0x89 can't start UTF-8 text,
+/// so the body is never confused with the line format of earlier versions.
+const MAGIC: &[u8] = b"\x89TKB";
+const VERSION: u8 = 1;
+
+/// Tag and value of the tag-length-value encoding. Numbers are stored as varint values,
+/// texts as UTF-8 and nested structures as encoded fields.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    pub tag: u64,
+    pub value: Vec<u8>
+}
+
+impl Field {
+    pub fn bytes(tag: u64, value: &[u8]) -> Field {
+        Field { tag, value: value.to_vec() }
+    }
+
+    pub fn text(tag: u64, value: &str) -> Field {
+        Field::bytes(tag, value.as_bytes())
+    }
+
+    pub fn number(tag: u64, value: u64) -> Field {
+        let mut encoded = Vec::new();
+        write_varint(&mut encoded, value);
+        Field { tag, value: encoded }
+    }
+
+    pub fn to_text(&self) -> Result<String, Error> {
+        String::from_utf8(self.value.clone()).map_err(|_| Error::CorruptedFileContent)
+    }
+
+    pub fn to_number(&self) -> Result<u64, Error> {
+        let mut pos = 0;
+        let number = read_varint(&self.value, &mut pos).ok_or(Error::CorruptedFileContent)?;
+        if pos != self.value.len() {
+            return Err(Error::CorruptedFileContent)
+        }
+        Ok(number)
+    }
+}
+
+/// Fields one after another as varint tag, varint length and the value.
+pub fn encode_fields(fields: &[Field]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for field in fields {
+        write_varint(&mut out, field.tag);
+        write_varint(&mut out, field.value.len() as u64);
+        out.extend_from_slice(&field.value);
+    }
+    out
+}
+
+/// Fields up to the first one cut off or otherwise malformed, and the offset of that one if there is any.
+pub fn read_fields(data: &[u8]) -> (Vec<Field>, Option<usize>) {
+    let mut fields = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let start = pos;
+        let header = read_varint(data, &mut pos).and_then(|tag| Some((tag, read_varint(data, &mut pos)?)));
+        match header {
+            Some((tag, len)) if len <= (data.len() - pos) as u64 => {
+                let end = pos + len as usize;
+                fields.push(Field::bytes(tag, &data[pos..end]));
+                pos = end;
+            },
+            _ => return (fields, Some(start))
+        }
+    }
+    (fields, None)
+}
+
+/// Whether the body is in this encoding rather than the line format of earlier versions.
+pub fn is_structured(body: &[u8]) -> bool {
+    body.starts_with(MAGIC)
+}
+
+/// Magic, version and the top level fields.
+pub fn encode(fields: &[Field]) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    out.push(VERSION);
+    out.extend(encode_fields(fields));
+    out
+}
+
+/// Top level fields of the body, as `read_fields` with the offset counted from the start of the body.
+/// Bodies of a newer, incompatible version are refused; new fields don't need a new version,
+/// as readers keep the fields they don't know.
+pub fn decode(body: &[u8]) -> Result<(Vec<Field>, Option<usize>), Error> {
+    let header_len = MAGIC.len() + 1;
+    if !is_structured(body) || body.len() < header_len {
+        return Err(Error::CorruptedFileContent)
+    }
+    if body[MAGIC.len()] != VERSION {
+        return Err(Error::UnsupportedFileVersion)
+    }
+    let (fields, damaged) = read_fields(&body[header_len..]);
+    Ok((fields, damaged.map(|offset| offset + header_len)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers() {
+        for &value in &[0, 1, 127, 128, 16_383, 16_384, u32::max_value() as u64, u64::max_value()] {
+            assert_eq!(Field::number(1, value).to_number().unwrap(), value);
+        }
+        assert_eq!(Field::number(1, 300).value, vec![0xac, 0x02]);
+        // cut off, trailing byte, longer than 64 bits
+        assert!(Field::bytes(1, &[0x80]).to_number().is_err());
+        assert!(Field::bytes(1, &[0x01, 0x00]).to_number().is_err());
+        assert!(Field::bytes(1, &[0xff; 11]).to_number().is_err());
+    }
+
+    #[test]
+    fn texts() {
+        assert_eq!(Field::text(1, "Привет").to_text().unwrap(), "Привет");
+        assert!(Field::bytes(1, b"\xff").to_text().is_err());
+    }
+
+    #[test]
+    fn round_trip() {
+        let fields = vec![
+            Field::text(1, "account"),
+            Field::bytes(2, &[]),
+            Field::number(300, 42),
+            Field::bytes(3, &[0u8; 200])
+        ];
+        let encoded = encode(&fields);
+        assert!(is_structured(&encoded));
+        assert_eq!(decode(&encoded).unwrap(), (fields, None));
+        assert_eq!(decode(&encode(&[])).unwrap(), (Vec::new(), None));
+    }
+
+    #[test]
+    fn unknown_fields_are_kept() {
+        // fields of a newer version are read as any other and written back unchanged
+        let newer = vec![Field::text(1, "known"), Field::bytes(u64::max_value(), b"\x00future\xff")];
+        let (fields, damaged) = decode(&encode(&newer)).unwrap();
+        assert_eq!(damaged, None);
+        assert_eq!(encode(&fields), encode(&newer));
+    }
+
+    #[test]
+    fn truncated() {
+        let fields = vec![Field::text(1, "first"), Field::text(2, "second")];
+        let encoded = encode_fields(&fields);
+        let second = encode_fields(&fields[..1]).len();
+        for cut in second + 1..encoded.len() {
+            assert_eq!(read_fields(&encoded[..cut]), (fields[..1].to_vec(), Some(second)), "cut at {}", cut);
+        }
+        assert_eq!(read_fields(&encoded[..second]), (fields[..1].to_vec(), None));
+        // offsets of the body count the magic and version
+        let body = encode(&fields);
+        assert_eq!(decode(&body[..body.len() - 1]).unwrap(), (fields[..1].to_vec(), Some(MAGIC.len() + 1 + second)));
+    }
+
+    #[test]
+    fn headers() {
+        assert!(!is_structured(b"account\0SECRET\n"));
+        assert!(decode(b"account\0SECRET\n").is_err());
+        assert!(decode(MAGIC).is_err());
+        let mut newer = encode(&[]);
+        newer[MAGIC.len()] = VERSION + 1;
+        match decode(&newer) {
+            Err(Error::UnsupportedFileVersion) => (),
+            other => panic!("expected UnsupportedFileVersion, got {:?}", other)
+        }
+    }
+}
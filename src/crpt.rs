@@ -1,64 +1,223 @@
 use errors::{Error};
+use kdf::{Kdf, KDF_BLOCK_LEN};
 use utils::*;
 use rand::{OsRng, Rng};
 use crypto::chacha20::ChaCha20;
 use crypto::symmetriccipher::SynchronousStreamCipher;
 use crypto::poly1305::Poly1305;
 use crypto::mac::{Mac, MacResult};
+use crypto::util::fixed_time_eq;
 use std::ops::Range;
-use byteorder::{ByteOrder, BigEndian};
+use byteorder::{ByteOrder, BigEndian, LittleEndian};
+
+/// Every versioned file starts with these bytes followed by big endian u32 format version.
+/// Files written before the format was versioned start straight with the key salt.
+const MAGIC: &[u8] = b"TOTPKEEP";
+const VERSION_LEN: usize = 4;
+const CURRENT_FORMAT: Format = Format::V3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    /// salt|cost|nonce|body|tag, ChaCha20 + Poly1305 over the body only
+    Legacy,
+    /// magic|version|salt|cost|nonce|body|tag, ChaCha20 + Poly1305 over the body only
+    V1,
+    /// magic|version|salt|cost|nonce|body|tag, XChaCha20-Poly1305 AEAD
+    /// with everything before the body as associated data
+    V2,
+    /// magic|version|salt|kdf|nonce|body|tag, same as V2 with selectable KDF
+    V3
+}
+
+impl Format {
+    fn detect(content: &[u8]) -> Result<Format, Error> {
+        if content.len() < MAGIC.len() + VERSION_LEN || &content[..MAGIC.len()] != MAGIC {
+            return Ok(Format::Legacy)
+        }
+        match BigEndian::read_u32(&content[MAGIC.len()..MAGIC.len() + VERSION_LEN]) {
+            1 => Ok(Format::V1),
+            2 => Ok(Format::V2),
+            3 => Ok(Format::V3),
+            _ => Err(Error::UnsupportedFileVersion)
+        }
+    }
+
+    fn version(&self) -> u32 {
+        match *self {
+            Format::Legacy => 0,
+            Format::V1 => 1,
+            Format::V2 => 2,
+            Format::V3 => 3
+        }
+    }
+
+    fn prefix_len(&self) -> usize {
+        match *self {
+            Format::Legacy => 0,
+            _ => MAGIC.len() + VERSION_LEN
+        }
+    }
+
+    fn nonce_len(&self) -> usize {
+        match *self {
+            Format::Legacy | Format::V1 => 8,
+            Format::V2 | Format::V3 => 24
+        }
+    }
+
+    fn kdf_len(&self) -> usize {
+        match *self {
+            Format::Legacy | Format::V1 | Format::V2 => 4,
+            Format::V3 => KDF_BLOCK_LEN
+        }
+    }
+}
+
+const KEY_SALT_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+
+/// Key derived from the password together with the salt and KDF it was derived with.
+/// Saving with the same `FileKey` avoids running the KDF again, only the nonce is renewed.
+pub struct FileKey {
+    pub salt: Vec<u8>,
+    pub kdf: Kdf,
+    key: Vec<u8>
+}
+
+impl FileKey {
+    /// Derives key for the new file with fresh random salt.
+    pub fn derive(password: &str, kdf: Kdf) -> Result<FileKey, Error> {
+        kdf.validate()?;
+        let mut salt = vec![0u8; KEY_SALT_LEN];
+        let mut gen = OsRng::new().expect("Failed to get OS random generator");
+        gen.fill_bytes(&mut salt[..]);
+        let key = kdf.build_key(password, &salt[..], KEY_LEN)?;
+        Ok(FileKey { salt, kdf, key })
+    }
+
+    /// Restores key from the `context()` and raw key bytes, as kept by the agent.
+    pub fn from_context(context: &[u8], key: &[u8]) -> Result<FileKey, Error> {
+        if context.len() != KEY_SALT_LEN + KDF_BLOCK_LEN || key.len() != KEY_LEN {
+            return Err(Error::CorruptedFileContent)
+        }
+        Ok(FileKey {
+            salt: context[..KEY_SALT_LEN].to_vec(),
+            kdf: Kdf::read(&context[KEY_SALT_LEN..])?,
+            key: key.to_vec()
+        })
+    }
+
+    /// Salt and KDF parameters block. Identifies the key without revealing it.
+    pub fn context(&self) -> Vec<u8> {
+        let mut context = vec![0u8; KEY_SALT_LEN + KDF_BLOCK_LEN];
+        copy_memory(&self.salt[..], &mut context[..KEY_SALT_LEN]);
+        self.kdf.write(&mut context[KEY_SALT_LEN..]);
+        context
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.key[..]
+    }
+}
+
+impl Drop for FileKey {
+    fn drop(&mut self) {
+        zero(&mut self.key[..]);
+    }
+}
 
 struct RegistryFile {
+    pub format: Format,
     pub decrypted_len: usize,
     pub key_salt_len: usize,
     pub chacha_nonce_len: usize,
     pub tag_len: usize,
-    pub bcrypt_pbkdf_cost: u32
+    pub kdf: Kdf
 }
 
 impl RegistryFile {
-    pub fn new_with_decrypted_length(len: usize) -> Self {
+    fn new(format: Format, decrypted_len: usize) -> Self {
         RegistryFile {
-            decrypted_len: len,
-            key_salt_len: 16,
-            chacha_nonce_len: 8,
+            format,
+            decrypted_len,
+            key_salt_len: KEY_SALT_LEN,
+            chacha_nonce_len: format.nonce_len(),
             tag_len: 16,
-            bcrypt_pbkdf_cost: 16
+            kdf: Kdf::default()
         }
     }
 
-    pub fn new_with_encrypted_length(len: usize) -> Result<Self, Error> {
-        if len < 44 {
+    pub fn new_with_decrypted_length(len: usize, kdf: Kdf) -> Self {
+        let mut file = RegistryFile::new(CURRENT_FORMAT, len);
+        file.kdf = kdf;
+        file
+    }
+
+    pub fn new_with_encrypted_content(content: &[u8]) -> Result<Self, Error> {
+        let format = Format::detect(content)?;
+        let overhead = RegistryFile::new(format, 0).encrypted_len();
+        if content.len() < overhead {
             return Err(Error::CorruptedFileContent)
         }
-        Ok(RegistryFile {
-            decrypted_len: len - 44,
-            key_salt_len: 16,
-            chacha_nonce_len: 8,
-            tag_len: 16,
-            bcrypt_pbkdf_cost: 16
-        })
+        let mut file = RegistryFile::new(format, content.len() - overhead);
+        file.kdf = match format {
+            Format::V3 => Kdf::read(&content[file.kdf_params()])?,
+            _ => Kdf::read_cost(&content[file.kdf_params()])?
+        };
+        Ok(file)
     }
 
-    pub fn encrypt(&self, body: &[u8], password: &str) -> Vec<u8> {
+    pub fn encrypt(&self, body: &[u8], key: &FileKey) -> Vec<u8> {
         let mut content: Vec<u8> = vec![0u8; self.encrypted_len()];
-        self.fill_header(&mut content[..]);
-        let mut key: Vec<u8> = build_key(password, &content[self.key_salt()], self.bcrypt_pbkdf_cost);
-        self.encrypt_body(&key[..], &mut content[..], body);
-        self.fill_mac(&key[..], &mut content[..]);
+        self.fill_header(&mut content[..], key);
+        self.seal_body(key.bytes(), &mut content[..], body);
         content
     }
 
-    pub fn decrypt(&self, encrypted: &Vec<u8>, password: &str) -> Result<Vec<u8>, Error> {
-        let mut key = self.extract_key(&encrypted[..], password);
-        self.validate_mac(&key[..], &encrypted[..])?;
-        Ok(self.decrypt_body(&key[..], &encrypted[..]))
+    /// Decrypted body and the key when the content is in the current format and the key can be reused.
+    pub fn decrypt(&self, encrypted: &Vec<u8>, password: &str) -> Result<(Vec<u8>, Option<FileKey>), Error> {
+        match self.format {
+            Format::Legacy | Format::V1 => {
+                let mut key = self.extract_key(&encrypted[..], password, 64)?;
+                let result = self.validate_mac(&key[..], &encrypted[..])
+                    .map(|_| (self.decrypt_body(&key[..], &encrypted[..]), None));
+                zero(&mut key[..]);
+                result
+            },
+            Format::V2 => {
+                let mut key = self.extract_key(&encrypted[..], password, KEY_LEN)?;
+                let result = self.open_body(&key[..], &encrypted[..]).map(|body| (body, None));
+                zero(&mut key[..]);
+                result
+            },
+            Format::V3 => {
+                let key = FileKey {
+                    salt: encrypted[self.key_salt()].to_vec(),
+                    kdf: self.kdf,
+                    key: self.extract_key(&encrypted[..], password, KEY_LEN)?
+                };
+                self.open_body(key.bytes(), &encrypted[..]).map(|body| (body, Some(key)))
+            }
+        }
+    }
+
+    pub fn decrypt_with_key(&self, encrypted: &Vec<u8>, key: &FileKey) -> Result<Vec<u8>, Error> {
+        if self.format != Format::V3 || self.key_context(encrypted) != Some(key.context()) {
+            return Err(Error::WrongPassword)
+        }
+        self.open_body(key.bytes(), &encrypted[..])
+    }
+
+    fn key_context(&self, encrypted: &[u8]) -> Option<Vec<u8>> {
+        match self.format {
+            Format::V3 => Some(encrypted[self.key_salt().start..self.kdf_params().end].to_vec()),
+            _ => None
+        }
     }
 
-    fn extract_key(&self, encrypted: &[u8], password: &str) -> Vec<u8> {
+    fn extract_key(&self, encrypted: &[u8], password: &str, key_len: usize) -> Result<Vec<u8>, Error> {
         let key_salt = &encrypted[self.key_salt()] as &[u8];
-        let bcrypt_pbkdf_cost = BigEndian::read_u32(&encrypted[self.bcrypt_pbkdf()]);
-        build_key(password, key_salt, bcrypt_pbkdf_cost)
+        self.kdf.build_key(password, key_salt, key_len)
     }
 
     fn validate_mac(&self, key: &[u8], encrypted: &[u8]) -> Result<(), Error> {
@@ -83,41 +242,70 @@ impl RegistryFile {
         decrypted_body
     }
 
-    fn fill_header(&self, content: &mut [u8]) {
+    fn open_body(&self, key: &[u8], encrypted: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut decrypted_body: Vec<u8> = vec![0u8; self.decrypted_len];
+        let mut chacha = ChaCha20::new_xchacha20(key, &encrypted[self.chacha_nonce()]);
+        let expected = aead_tag(&mut chacha, &encrypted[self.header()], &encrypted[self.body()]);
+        if !fixed_time_eq(&expected, &encrypted[self.tag()]) {
+            // header tampering and a wrong password are indistinguishable here
+            return Err(Error::WrongPassword);
+        }
+        if self.decrypted_len > 0 {
+            chacha.process(&encrypted[self.body()], &mut decrypted_body[..]);
+        }
+        Ok(decrypted_body)
+    }
+
+    fn fill_header(&self, content: &mut [u8], key: &FileKey) {
+        copy_memory(MAGIC, &mut content[self.magic()]);
+        BigEndian::write_u32(&mut content[self.version()], self.format.version());
+        copy_memory(&key.salt[..], &mut content[self.key_salt()]);
+        key.kdf.write(&mut content[self.kdf_params()]);
         let mut gen = OsRng::new().expect("Failed to get OS random generator");
-        gen.fill_bytes(&mut content[self.key_salt()]);
-        BigEndian::write_u32(&mut content[self.bcrypt_pbkdf()], self.bcrypt_pbkdf_cost);
         gen.fill_bytes(&mut content[self.chacha_nonce()]);
     }
 
-    fn encrypt_body(&self, key: &[u8], content: &mut [u8], input: &[u8]) {
+    fn seal_body(&self, key: &[u8], content: &mut [u8], input: &[u8]) {
+        let mut chacha = ChaCha20::new_xchacha20(key, &content[self.chacha_nonce()]);
+        let mut poly_key = poly1305_key(&mut chacha);
         if self.decrypted_len > 0 {
-            let mut chacha = ChaCha20::new(&key[..32], &content[self.chacha_nonce()]);
             chacha.process(input, &mut content[self.body()]);
         }
+        let tag = aead_mac(&poly_key, &content[self.header()], &content[self.body()]);
+        zero(&mut poly_key);
+        copy_memory(&tag, &mut content[self.tag()]);
     }
 
-    fn fill_mac(&self, key: &[u8], content: &mut [u8]) {
-        let mut poly1305 = Poly1305::new(&key[32..64]);
-        poly1305.input(&content[self.body()]);
-        let mac = poly1305.result();
-        copy_memory(mac.code(), &mut content[self.tag()]);
+    fn magic(&self) -> Range<usize> {
+        (0..MAGIC.len())
+    }
+
+    fn version(&self) -> Range<usize> {
+        let start = self.magic().end;
+        (start..(start + VERSION_LEN))
     }
 
     fn key_salt(&self) -> Range<usize> {
-        (0..self.key_salt_len)
+        let start = self.format.prefix_len();
+        (start..(start + self.key_salt_len))
     }
 
-    fn bcrypt_pbkdf(&self) ->  Range<usize> {
+    /// Bare bcrypt_pbkdf cost before V3, KDF id and parameters since V3.
+    fn kdf_params(&self) ->  Range<usize> {
         let start = self.key_salt().end;
-        (start..(start + 4))
+        (start..(start + self.format.kdf_len()))
     }
 
     fn chacha_nonce(&self) -> Range<usize> {
-        let start = self.bcrypt_pbkdf().end;
+        let start = self.kdf_params().end;
         (start..(start + self.chacha_nonce_len))
     }
 
+    /// Everything preceding the ciphertext. Authenticated as associated data since V2.
+    fn header(&self) -> Range<usize> {
+        (0..self.chacha_nonce().end)
+    }
+
     fn body(&self) ->  Range<usize> {
         let start = self.chacha_nonce().end;
         (start..(start + self.decrypted_len))
@@ -133,20 +321,162 @@ impl RegistryFile {
     }
 }
 
+/// One-time Poly1305 key is the first 32 bytes of the keystream block 0 (RFC 8439, 2.6).
+/// Leaves the cipher positioned at block 1, where encryption starts.
+fn poly1305_key(chacha: &mut ChaCha20) -> [u8; 32] {
+    let zeros = [0u8; 64];
+    let mut block = [0u8; 64];
+    chacha.process(&zeros, &mut block);
+    let mut key = [0u8; 32];
+    copy_memory(&block[..32], &mut key);
+    zero(&mut block);
+    key
+}
+
+/// Poly1305 over `aad | pad16 | ciphertext | pad16 | le64(aad len) | le64(ciphertext len)`
+/// as in RFC 8439, 2.8.
+fn aead_mac(poly_key: &[u8], aad: &[u8], ciphertext: &[u8]) -> [u8; 16] {
+    let padding = [0u8; 16];
+    let mut lengths = [0u8; 16];
+    LittleEndian::write_u64(&mut lengths[..8], aad.len() as u64);
+    LittleEndian::write_u64(&mut lengths[8..], ciphertext.len() as u64);
+
+    let mut poly1305 = Poly1305::new(poly_key);
+    poly1305.input(aad);
+    poly1305.input(&padding[..(16 - aad.len() % 16) % 16]);
+    poly1305.input(ciphertext);
+    poly1305.input(&padding[..(16 - ciphertext.len() % 16) % 16]);
+    poly1305.input(&lengths);
+    let mut tag = [0u8; 16];
+    poly1305.raw_result(&mut tag);
+    tag
+}
+
+fn aead_tag(chacha: &mut ChaCha20, aad: &[u8], ciphertext: &[u8]) -> [u8; 16] {
+    let mut poly_key = poly1305_key(chacha);
+    let tag = aead_mac(&poly_key, aad, ciphertext);
+    zero(&mut poly_key);
+    tag
+}
 
-fn build_key(password: &str, salt: &[u8], rounds: u32) -> Vec<u8> {
-    use crypto::bcrypt_pbkdf::bcrypt_pbkdf;
-    let mut output: Vec<u8> = vec![0u8; 64];
-    bcrypt_pbkdf(password.as_bytes(), salt, rounds, &mut output);
-    output
+pub fn encrypt(body: &[u8], key: &FileKey) -> Vec<u8> {
+    let file = RegistryFile::new_with_decrypted_length(body.len(), key.kdf);
+    file.encrypt(body, key)
 }
 
-pub fn encrypt(body: &[u8], password: &str) -> Vec<u8> {
-    let file = RegistryFile::new_with_decrypted_length(body.len());
-    file.encrypt(body, password)
+/// KDF with parameters the encrypted content was written with.
+pub fn read_kdf(encrypted: &Vec<u8>) -> Result<Kdf, Error> {
+    let file = RegistryFile::new_with_encrypted_content(&encrypted[..])?;
+    Ok(file.kdf)
 }
 
-pub fn decrypt(encrypted: &Vec<u8>, password: &str) -> Result<Vec<u8>, Error> {
-    let file = RegistryFile::new_with_encrypted_length(encrypted.len())?;
+/// Salt and KDF parameters of the content, see `FileKey::context`.
+/// None for formats older than the current one, their keys are never reused.
+pub fn key_context(encrypted: &Vec<u8>) -> Result<Option<Vec<u8>>, Error> {
+    let file = RegistryFile::new_with_encrypted_content(&encrypted[..])?;
+    Ok(file.key_context(&encrypted[..]))
+}
+
+pub fn decrypt(encrypted: &Vec<u8>, password: &str) -> Result<(Vec<u8>, Option<FileKey>), Error> {
+    let file = RegistryFile::new_with_encrypted_content(&encrypted[..])?;
     file.decrypt(encrypted, password)
-}
\ No newline at end of file
+}
+
+pub fn decrypt_with_key(encrypted: &Vec<u8>, key: &FileKey) -> Result<Vec<u8>, Error> {
+    let file = RegistryFile::new_with_encrypted_content(&encrypted[..])?;
+    file.decrypt_with_key(encrypted, key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PASSWORD: &str = "password";
+    const BODY: &[u8] = b"totpkeep test body";
+
+    // cheap parameters, every tampered header runs the KDF again
+    fn kdfs() -> Vec<Kdf> {
+        vec![Kdf::BcryptPbkdf { cost: 2 }, Kdf::Argon2id { memory: 8, iterations: 1, parallelism: 1 }]
+    }
+
+    /// V2 content as the previous version wrote it, bare bcrypt_pbkdf cost in place of the KDF block.
+    fn seal_v2(body: &[u8], password: &str, cost: u32) -> Vec<u8> {
+        let file = RegistryFile::new(Format::V2, body.len());
+        let mut content = vec![0u8; file.encrypted_len()];
+        copy_memory(MAGIC, &mut content[file.magic()]);
+        BigEndian::write_u32(&mut content[file.version()], 2);
+        BigEndian::write_u32(&mut content[file.kdf_params()], cost);
+        content[file.chacha_nonce()].iter_mut().enumerate().for_each(|(i, b)| *b = i as u8);
+        let key = Kdf::BcryptPbkdf { cost }.build_key(password, &content[file.key_salt()], KEY_LEN).unwrap();
+        file.seal_body(&key[..], &mut content[..], body);
+        content
+    }
+
+    /// Every single byte flip of the header must be refused. Where the header still parses,
+    /// the refusal must come from the tag check rather than from the changed field.
+    fn assert_header_authenticated(content: &Vec<u8>) {
+        let header_len = RegistryFile::new_with_encrypted_content(&content[..]).unwrap().header().end;
+        for index in 0..header_len {
+            let mut tampered = content.clone();
+            tampered[index] ^= 0x01;
+            let result = decrypt(&tampered, PASSWORD);
+            assert!(result.is_err(), "flipped header byte {} was accepted", index);
+            if read_kdf(&tampered).is_ok() && Format::detect(&tampered[..]).ok() == Format::detect(&content[..]).ok() {
+                match result {
+                    Err(Error::WrongPassword) => (),
+                    Err(other) => panic!("flipped header byte {} failed with {} instead of the tag check", index, other),
+                    Ok(_) => unreachable!()
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        for kdf in kdfs() {
+            let key = FileKey::derive(PASSWORD, kdf).unwrap();
+            let content = encrypt(BODY, &key);
+            let (body, reusable) = decrypt(&content, PASSWORD).unwrap();
+            assert_eq!(&body[..], BODY);
+            assert_eq!(reusable.unwrap().context(), key.context());
+            assert_eq!(&decrypt_with_key(&content, &key).unwrap()[..], BODY);
+        }
+        let (body, reusable) = decrypt(&seal_v2(BODY, PASSWORD, 2), PASSWORD).unwrap();
+        assert_eq!(&body[..], BODY);
+        assert!(reusable.is_none());
+    }
+
+    #[test]
+    fn wrong_password() {
+        let key = FileKey::derive(PASSWORD, kdfs()[0]).unwrap();
+        match decrypt(&encrypt(BODY, &key), "other") {
+            Err(Error::WrongPassword) => (),
+            other => panic!("expected WrongPassword, got {:?}", other.map(|(body, _)| body))
+        }
+    }
+
+    #[test]
+    fn v3_header_is_authenticated() {
+        for kdf in kdfs() {
+            let key = FileKey::derive(PASSWORD, kdf).unwrap();
+            assert_header_authenticated(&encrypt(BODY, &key));
+        }
+    }
+
+    #[test]
+    fn v2_header_is_authenticated() {
+        assert_header_authenticated(&seal_v2(BODY, PASSWORD, 2));
+    }
+
+    #[test]
+    fn body_and_tag_are_authenticated() {
+        let key = FileKey::derive(PASSWORD, kdfs()[0]).unwrap();
+        let content = encrypt(BODY, &key);
+        let header_len = RegistryFile::new_with_encrypted_content(&content[..]).unwrap().header().end;
+        for index in header_len..content.len() {
+            let mut tampered = content.clone();
+            tampered[index] ^= 0x80;
+            assert!(decrypt_with_key(&tampered, &key).is_err(), "flipped byte {} was accepted", index);
+        }
+    }
+}
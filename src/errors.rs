@@ -6,8 +6,41 @@ pub enum Error {
     FileNotFound,
     FileError,
     CorruptedFileContent,
+    UnsupportedFileVersion,
     WrongPassword,
+    WrongKdfParameters,
     WrongServiceRecordData,
+    WrongServiceRecordParameters,
+    WrongUri,
+    WrongRecordIndex,
+    PasswordPromptFailed,
+    PasswordSourceFailed,
+    EmptyPassword,
+    PasswordsDoNotMatch,
+    RecryptVerificationFailed,
+    AgentNotRunning,
+    AgentAlreadyRunning,
+    AgentFailure,
+    AgentNotTrusted,
+    BackupNotFound,
+    FileLocked,
+    NotHotpRecord,
+    HotpResyncFailed,
+    WrongMigrationPayload,
+    WrongAegisFile,
+    FileExists,
+    WrongImage,
+    QrCodeNotFound,
+    QrEncodingFailed,
+    UnsupportedImageFormat,
+    NotConfirmed,
+    RecordNotFound,
+    AmbiguousRecord(Vec<String>),
+    CorruptedRecord(usize, String),
+    CodeExpiresSoon(u64),
+    WrongColumns(String),
+    WrongConfig(usize, String),
+    ViewNotFound(String),
     UnknownCommand
 }
 
@@ -23,8 +56,41 @@ impl fmt::Display for Error {
             Error::FileNotFound => f.write_str("FileNotFound"),
             Error::FileError => f.write_str("FileEror"),
             Error::CorruptedFileContent => f.write_str("CorruptedFileContent"),
+            Error::UnsupportedFileVersion => f.write_str("UnsupportedFileVersion"),
             Error::WrongPassword => f.write_str("WrongPassword"),
+            Error::WrongKdfParameters => f.write_str("WrongKdfParameters"),
             Error::WrongServiceRecordData => f.write_str("WrongServiceRecord"),
+            Error::WrongServiceRecordParameters => f.write_str("WrongServiceRecordParameters"),
+            Error::WrongUri => f.write_str("WrongUri"),
+            Error::WrongRecordIndex => f.write_str("WrongRecordIndex"),
+            Error::PasswordPromptFailed => f.write_str("PasswordPromptFailed"),
+            Error::PasswordSourceFailed => f.write_str("PasswordSourceFailed"),
+            Error::EmptyPassword => f.write_str("EmptyPassword"),
+            Error::PasswordsDoNotMatch => f.write_str("PasswordsDoNotMatch"),
+            Error::RecryptVerificationFailed => f.write_str("RecryptVerificationFailed"),
+            Error::AgentNotRunning => f.write_str("AgentNotRunning"),
+            Error::AgentAlreadyRunning => f.write_str("AgentAlreadyRunning"),
+            Error::AgentFailure => f.write_str("AgentFailure"),
+            Error::AgentNotTrusted => f.write_str("AgentNotTrusted"),
+            Error::BackupNotFound => f.write_str("BackupNotFound"),
+            Error::FileLocked => f.write_str("FileLocked"),
+            Error::NotHotpRecord => f.write_str("NotHotpRecord"),
+            Error::HotpResyncFailed => f.write_str("HotpResyncFailed"),
+            Error::WrongMigrationPayload => f.write_str("WrongMigrationPayload"),
+            Error::WrongAegisFile => f.write_str("WrongAegisFile"),
+            Error::FileExists => f.write_str("FileExists"),
+            Error::WrongImage => f.write_str("WrongImage"),
+            Error::QrCodeNotFound => f.write_str("QrCodeNotFound"),
+            Error::QrEncodingFailed => f.write_str("QrEncodingFailed"),
+            Error::UnsupportedImageFormat => f.write_str("UnsupportedImageFormat"),
+            Error::NotConfirmed => f.write_str("NotConfirmed"),
+            Error::RecordNotFound => f.write_str("RecordNotFound"),
+            Error::AmbiguousRecord(ref candidates) => write!(f, "AmbiguousRecord, matches:\n  {}", candidates.join("\n  ")),
+            Error::CorruptedRecord(number, ref reason) => write!(f, "CorruptedRecord, record {}: {}", number, reason),
+            Error::CodeExpiresSoon(left) => write!(f, "CodeExpiresSoon, valid for {} more seconds", left),
+            Error::WrongColumns(ref reason) => write!(f, "WrongColumns, {}", reason),
+            Error::WrongConfig(line, ref reason) => write!(f, "WrongConfig, line {}: {}", line, reason),
+            Error::ViewNotFound(ref name) => write!(f, "ViewNotFound, {}", name),
             Error::UnknownCommand => f.write_str("UnknownCommand"),
         }
     }
@@ -37,8 +103,41 @@ impl StdError for Error {
             Error::FileNotFound => "File not found",
             Error::FileError => "File operation error",
             Error::CorruptedFileContent => "File is corrupted",
+            Error::UnsupportedFileVersion => "File was written by newer version of totpkeep",
             Error::WrongPassword => "Wrong password",
+            Error::WrongKdfParameters => "Unsupported key derivation parameters",
             Error::WrongServiceRecordData => "Unable to parse TOTP secret",
+            Error::WrongServiceRecordParameters => "Unsupported TOTP digits, period or algorithm, or T0 in the future",
+            Error::WrongUri => "Unable to parse otpauth:// URI",
+            Error::WrongRecordIndex => "No record with such index",
+            Error::PasswordPromptFailed => "Unable to read password from the terminal",
+            Error::PasswordSourceFailed => "Unable to read password from the file descriptor or password file",
+            Error::EmptyPassword => "Password can not be empty",
+            Error::PasswordsDoNotMatch => "Passwords do not match",
+            Error::RecryptVerificationFailed => "Re-encrypted file can not be read back, original file is kept",
+            Error::AgentNotRunning => "Agent is not running or TOTPKEEP_AGENT_SOCK is not set",
+            Error::AgentAlreadyRunning => "Agent is already running on this socket",
+            Error::AgentFailure => "Agent request failed",
+            Error::AgentNotTrusted => "Agent socket or its directory belongs to another user or is accessible to others",
+            Error::BackupNotFound => "No backup with such number",
+            Error::FileLocked => "File is being modified by another totpkeep process",
+            Error::NotHotpRecord => "Record is not HOTP",
+            Error::HotpResyncFailed => "Codes not found in the look-ahead window",
+            Error::WrongMigrationPayload => "Wrong otpauth-migration data",
+            Error::WrongAegisFile => "Wrong Aegis vault file",
+            Error::FileExists => "File already exists",
+            Error::WrongImage => "Unable to read image",
+            Error::QrCodeNotFound => "No QR code found in the image",
+            Error::QrEncodingFailed => "Unable to make QR code",
+            Error::UnsupportedImageFormat => "Only PNG and SVG files are supported",
+            Error::NotConfirmed => "Not confirmed",
+            Error::RecordNotFound => "No record with this name",
+            Error::AmbiguousRecord(_) => "More than one record matches",
+            Error::CorruptedRecord(_, _) => "Record in the file can't be read",
+            Error::CodeExpiresSoon(_) => "Code expires too soon to be copied, wait for the next one or use --force",
+            Error::WrongColumns(_) => "Unknown column or alignment",
+            Error::WrongConfig(_, _) => "Config file can't be read",
+            Error::ViewNotFound(_) => "No view with this name in the config file",
             Error::UnknownCommand => "Unknown Command",
         }
     }
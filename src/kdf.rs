@@ -0,0 +1,112 @@
+use errors::{Error};
+use byteorder::{ByteOrder, BigEndian};
+
+/// Length of KDF parameters block in the file header:
+/// big endian u32 KDF id followed by three big endian u32 parameters.
+pub const KDF_BLOCK_LEN: usize = 16;
+
+const BCRYPT_PBKDF_ID: u32 = 1;
+const ARGON2ID_ID: u32 = 2;
+
+// Parameters above these limits are treated as corruption rather than
+// running the KDF practically forever or exhausting memory.
+const MAX_BCRYPT_PBKDF_COST: u32 = 1024;
+const MAX_ARGON2_MEMORY: u32 = 4 * 1024 * 1024;
+const MAX_ARGON2_ITERATIONS: u32 = 64;
+const MAX_ARGON2_PARALLELISM: u32 = 64;
+
+/// Password based key derivation function together with its cost parameters.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kdf {
+    BcryptPbkdf { cost: u32 },
+    /// `memory` is in KiB
+    Argon2id { memory: u32, iterations: u32, parallelism: u32 }
+}
+
+impl Kdf {
+    pub fn default() -> Self {
+        Kdf::BcryptPbkdf { cost: 16 }
+    }
+
+    pub fn default_argon2id() -> Self {
+        Kdf::Argon2id { memory: 64 * 1024, iterations: 3, parallelism: 4 }
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        let valid = match *self {
+            Kdf::BcryptPbkdf { cost } => cost > 0 && cost <= MAX_BCRYPT_PBKDF_COST,
+            Kdf::Argon2id { memory, iterations, parallelism } =>
+                parallelism > 0 && parallelism <= MAX_ARGON2_PARALLELISM &&
+                iterations > 0 && iterations <= MAX_ARGON2_ITERATIONS &&
+                memory >= 8 * parallelism && memory <= MAX_ARGON2_MEMORY
+        };
+        if !valid {
+            return Err(Error::WrongKdfParameters)
+        }
+        Ok(())
+    }
+
+    /// Reads bare bcrypt_pbkdf cost used by the files before KDF was selectable.
+    pub fn read_cost(block: &[u8]) -> Result<Kdf, Error> {
+        let kdf = Kdf::BcryptPbkdf { cost: BigEndian::read_u32(block) };
+        kdf.validate().map_err(|_| Error::CorruptedFileContent)?;
+        Ok(kdf)
+    }
+
+    pub fn read(block: &[u8]) -> Result<Kdf, Error> {
+        let kdf = match BigEndian::read_u32(&block[0..4]) {
+            BCRYPT_PBKDF_ID => Kdf::BcryptPbkdf { cost: BigEndian::read_u32(&block[4..8]) },
+            ARGON2ID_ID => Kdf::Argon2id {
+                memory: BigEndian::read_u32(&block[4..8]),
+                iterations: BigEndian::read_u32(&block[8..12]),
+                parallelism: BigEndian::read_u32(&block[12..16])
+            },
+            _ => return Err(Error::UnsupportedFileVersion)
+        };
+        kdf.validate().map_err(|_| Error::CorruptedFileContent)?;
+        Ok(kdf)
+    }
+
+    pub fn write(&self, block: &mut [u8]) {
+        match *self {
+            Kdf::BcryptPbkdf { cost } => {
+                BigEndian::write_u32(&mut block[0..4], BCRYPT_PBKDF_ID);
+                BigEndian::write_u32(&mut block[4..8], cost);
+                BigEndian::write_u32(&mut block[8..12], 0);
+                BigEndian::write_u32(&mut block[12..16], 0);
+            },
+            Kdf::Argon2id { memory, iterations, parallelism } => {
+                BigEndian::write_u32(&mut block[0..4], ARGON2ID_ID);
+                BigEndian::write_u32(&mut block[4..8], memory);
+                BigEndian::write_u32(&mut block[8..12], iterations);
+                BigEndian::write_u32(&mut block[12..16], parallelism);
+            }
+        }
+    }
+
+    pub fn build_key(&self, password: &str, salt: &[u8], key_len: usize) -> Result<Vec<u8>, Error> {
+        match *self {
+            Kdf::BcryptPbkdf { cost } => {
+                use crypto::bcrypt_pbkdf::bcrypt_pbkdf;
+                let mut output: Vec<u8> = vec![0u8; key_len];
+                bcrypt_pbkdf(password.as_bytes(), salt, cost, &mut output);
+                Ok(output)
+            },
+            Kdf::Argon2id { memory, iterations, parallelism } => {
+                use argon2::{hash_raw, Config, ThreadMode, Variant, Version};
+                let config = Config {
+                    variant: Variant::Argon2id,
+                    version: Version::Version13,
+                    mem_cost: memory,
+                    time_cost: iterations,
+                    lanes: parallelism,
+                    thread_mode: ThreadMode::from_threads(parallelism),
+                    secret: &[],
+                    ad: &[],
+                    hash_length: key_len as u32
+                };
+                hash_raw(password.as_bytes(), salt, &config).map_err(|_| Error::WrongKdfParameters)
+            }
+        }
+    }
+}
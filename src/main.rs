@@ -1,26 +1,116 @@
+extern crate argon2;
 extern crate base32;
+extern crate base64;
 extern crate byteorder;
+#[macro_use]
 extern crate clap;
 extern crate crypto;
+extern crate image;
+extern crate libc;
 extern crate oath;
+extern crate qrcode;
 extern crate rand;
+extern crate rpassword;
+extern crate rqrr;
+extern crate rustc_serialize;
+extern crate unicode_segmentation;
+extern crate unicode_width;
 
+mod aegis;
+mod agent;
+mod body;
 mod crpt;
 mod errors;
+mod kdf;
+mod migration;
+mod otpauth;
+mod password;
+mod qr;
+mod selector;
+mod storage;
 mod totpkeep;
+mod tui;
 mod utils;
+mod view;
 mod table;
+mod terminal;
 
-use clap::{App, Arg, SubCommand};
+use clap::{App, Arg, ArgMatches, SubCommand};
 use errors::{Error};
+use kdf::{Kdf};
+use totpkeep::{Algorithm, ListFormat, OtpKind, OtpParams, RecordChanges};
+
+fn otp_params(m: &ArgMatches) -> OtpParams {
+    OtpParams {
+        digits: value_t_or_exit!(m, "digits", u32),
+        period: value_t_or_exit!(m, "period", u64),
+        algorithm: Algorithm::parse(m.value_of("algorithm").unwrap()).unwrap(),
+        epoch: value_t_or_exit!(m, "epoch", u64)
+    }
+}
+
+fn otp_kind(m: &ArgMatches) -> OtpKind {
+    match m.is_present("hotp") {
+        true => OtpKind::Hotp { counter: value_t_or_exit!(m, "counter", u64) },
+        false => OtpKind::Totp
+    }
+}
+
+fn record_changes<'a>(m: &'a ArgMatches) -> RecordChanges<'a> {
+    RecordChanges {
+        account: m.value_of("account"),
+        issuer: m.value_of("issuer"),
+        notes: m.value_of("note"),
+        tags: m.values_of("tag").map(|v| v.collect()).unwrap_or_default(),
+        untags: m.values_of("untag").map(|v| v.collect()).unwrap_or_default()
+    }
+}
+
+/// Columns of `list` and `watch` tables: `--columns`, the `-l` set, a named view or the default view of the config file.
+fn view_columns(m: &ArgMatches) -> Result<Vec<view::ViewColumn>, Error> {
+    if let Some(spec) = m.value_of("columns") {
+        return view::parse_columns(spec).map_err(Error::WrongColumns)
+    }
+    if m.is_present("long") {
+        return Ok(view::default_columns(true))
+    }
+    Ok(view::load_view(m.value_of("view"))?.unwrap_or_else(|| view::default_columns(false)))
+}
+
+fn kdf(m: &ArgMatches) -> Option<Kdf> {
+    let kdf = match m.value_of("kdf") {
+        Some("bcrypt-pbkdf") => Kdf::default(),
+        Some("argon2id") => Kdf::default_argon2id(),
+        _ => return None
+    };
+    let param = |name: &str, default: u32| match m.is_present(name) {
+        true => value_t_or_exit!(m, name, u32),
+        false => default
+    };
+    Some(match kdf {
+        Kdf::BcryptPbkdf { cost } => Kdf::BcryptPbkdf { cost: param("cost", cost) },
+        Kdf::Argon2id { memory, iterations, parallelism } => Kdf::Argon2id {
+            memory: param("memory", memory),
+            iterations: param("iterations", iterations),
+            parallelism: param("parallelism", parallelism)
+        }
+    })
+}
 
 fn main() {
     let matches = App::new("totpkeep")
         .arg(Arg::with_name("password")
-            .help("password for the TOTP records file")
+            .help("password for the TOTP records file. Asked on the terminal when omitted")
             .short("p")
             .takes_value(true)
-            .required(true)
+        )
+        .arg(Arg::with_name("password-fd")
+            .help("read password from the first line of file descriptor <n>. \
+                   TOTPKEEP_PASSWORD_FILE environment variable can name a file to read it from instead")
+            .long("password-fd")
+            .value_name("n")
+            .takes_value(true)
+            .conflicts_with("password")
         )
         .arg(Arg::with_name("file")
             .help("TOTP records file. Default is ~/.config/totpkeep.tkp")
@@ -39,21 +129,216 @@ fn main() {
                     .help("Name. For example \"site1 MyUserName 2FA\"")
                     .index(1)
                     .takes_value(true)
-                    .required(true)
+                    .required_unless("qr")
                 )
                 .arg(Arg::with_name("secret")
                     .help("TOTP secret")
                     .index(2)
                     .takes_value(true)
+                    .required_unless("qr")
+                )
+                .arg(Arg::with_name("qr")
+                    .help("image with enrollment QR code. Name, secret and parameters are taken from its URI")
+                    .long("qr")
+                    .value_name("file")
+                    .takes_value(true)
+                    .conflicts_with_all(&["name", "secret"])
+                )
+                .arg(Arg::with_name("digits")
+                    .help("number of digits in the code")
+                    .long("digits")
+                    .takes_value(true)
+                    .default_value("6")
+                    .possible_values(&["6", "7", "8"])
+                )
+                .arg(Arg::with_name("period")
+                    .help("time step in seconds")
+                    .long("period")
+                    .takes_value(true)
+                    .default_value("30")
+                )
+                .arg(Arg::with_name("algorithm")
+                    .help("HMAC hash algorithm")
+                    .long("algorithm")
+                    .takes_value(true)
+                    .default_value("SHA1")
+                    .possible_values(&["SHA1", "SHA256", "SHA512"])
+                    .case_insensitive(true)
+                )
+                .arg(Arg::with_name("epoch")
+                    .help("T0, Unix time to start counting time steps from")
+                    .long("epoch")
+                    .takes_value(true)
+                    .default_value("0")
+                )
+                .arg(Arg::with_name("hotp")
+                    .help("counter based HOTP record instead of TOTP. Codes are taken with \"totpkeep next\"")
+                    .long("hotp")
+                )
+                .arg(Arg::with_name("counter")
+                    .help("initial HOTP counter")
+                    .long("counter")
+                    .takes_value(true)
+                    .default_value("0")
+                )
+                .arg(Arg::with_name("issuer")
+                    .help("service the account belongs to, like \"GitHub\"")
+                    .long("issuer")
+                    .takes_value(true)
+                )
+                .arg(Arg::with_name("note")
+                    .help("free-form notes, like recovery hints")
+                    .long("note")
+                    .takes_value(true)
+                )
+                .arg(Arg::with_name("tag")
+                    .help("tag for grouping and selecting records. Can be repeated")
+                    .long("tag")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("import-uri")
+                .about("Add records from otpauth:// or Google Authenticator otpauth-migration:// URIs")
+                .arg(Arg::with_name("uri")
+                    .help("URI like \"otpauth://totp/Issuer:account?secret=...&issuer=...\" \
+                           or \"otpauth-migration://offline?data=...\"")
+                    .index(1)
+                    .takes_value(true)
+                    .multiple(true)
                     .required(true)
                 )
         )
+        .subcommand(
+            SubCommand::with_name("export-uri")
+                .about("Print otpauth:// URI for one or all records")
+                .arg(Arg::with_name("record")
+                    .help("index, name, part of the name or id: of the record. All records are exported when omitted")
+                    .index(1)
+                    .takes_value(true)
+                )
+                .arg(Arg::with_name("google")
+                    .help("print otpauth-migration:// URIs for Google Authenticator \"Transfer accounts\"")
+                    .long("google")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Add records from export file of another application")
+                .arg(Arg::with_name("path")
+                    .help("exported file")
+                    .index(1)
+                    .takes_value(true)
+                    .required(true)
+                )
+                .arg(Arg::with_name("format")
+                    .help("format of the file")
+                    .long("format")
+                    .takes_value(true)
+                    .required(true)
+                    .possible_values(&["aegis"])
+                )
+                .arg(Arg::with_name("backup-password")
+                    .help("password of the encrypted export. Asked on the terminal when needed and omitted")
+                    .long("backup-password")
+                    .takes_value(true)
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Write all records to a new file for another application")
+                .arg(Arg::with_name("path")
+                    .help("file to create")
+                    .index(1)
+                    .takes_value(true)
+                    .required(true)
+                )
+                .arg(Arg::with_name("format")
+                    .help("format of the file")
+                    .long("format")
+                    .takes_value(true)
+                    .required(true)
+                    .possible_values(&["aegis"])
+                )
+                .arg(Arg::with_name("encrypt")
+                    .help("encrypt the file with a backup password, asked on the terminal unless --backup-password is given")
+                    .long("encrypt")
+                )
+                .arg(Arg::with_name("backup-password")
+                    .help("password to encrypt the file with")
+                    .long("backup-password")
+                    .takes_value(true)
+                    .requires("encrypt")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("show-qr")
+                .about("Show record as QR code to scan it with a phone. Reveals the secret, so asks for confirmation")
+                .arg(Arg::with_name("record")
+                    .help("index, name, part of the name or id: of the record")
+                    .index(1)
+                    .takes_value(true)
+                    .required(true)
+                )
+                .arg(Arg::with_name("output")
+                    .help("write the code to a new PNG or SVG file, depending on its extension, instead of the terminal")
+                    .long("output")
+                    .short("o")
+                    .value_name("file")
+                    .takes_value(true)
+                )
+                .arg(Arg::with_name("invert")
+                    .help("draw dark modules, for terminals with light background")
+                    .long("invert")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("edit")
+                .about("Change account name, issuer, notes or tags of a record")
+                .arg(Arg::with_name("record")
+                    .help("index, name, part of the name or id: of the record")
+                    .index(1)
+                    .takes_value(true)
+                    .required(true)
+                )
+                .arg(Arg::with_name("account")
+                    .help("new account name")
+                    .long("account")
+                    .takes_value(true)
+                )
+                .arg(Arg::with_name("issuer")
+                    .help("new issuer. Empty value removes it")
+                    .long("issuer")
+                    .takes_value(true)
+                )
+                .arg(Arg::with_name("note")
+                    .help("new notes, replacing the old ones. Empty value removes them")
+                    .long("note")
+                    .takes_value(true)
+                )
+                .arg(Arg::with_name("tag")
+                    .help("tag to add. Can be repeated")
+                    .long("tag")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                )
+                .arg(Arg::with_name("untag")
+                    .help("tag to remove. Can be repeated")
+                    .long("untag")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                )
+        )
         .subcommand(
             SubCommand::with_name("remove")
                 .about("Remove record")
                 // or "myapp help"
-                .arg(Arg::with_name("index")
-                    .help("index of the record. See index in the \"totpkeep list\" output")
+                .arg(Arg::with_name("record")
+                    .help("index, name, part of the name or id: of the record")
                     .index(1)
                     .takes_value(true)
                     .required(true)
@@ -62,42 +347,352 @@ fn main() {
         .subcommand(
             SubCommand::with_name("list")
                 .about("List codes for all records")
+                .arg(Arg::with_name("format")
+                    .help("output format. json, tsv and plain are meant for scripts")
+                    .long("format")
+                    .takes_value(true)
+                    .default_value("table")
+                    .possible_values(&["table", "json", "tsv", "plain"])
+                )
+                .arg(Arg::with_name("long")
+                    .help("also show ID, tags, last use and notes in the table")
+                    .long("long")
+                    .short("l")
+                )
+                .arg(Arg::with_name("columns")
+                    .help("comma separated columns of the table, each optionally followed by :left, :middle or :right. \
+                           Columns are index, name, issuer, account, id, tags, notes, previous, current, next, \
+                           seconds-left, period, algorithm and last-used")
+                    .long("columns")
+                    .value_name("columns")
+                    .takes_value(true)
+                    .conflicts_with_all(&["long", "view"])
+                )
+                .arg(Arg::with_name("view")
+                    .help("columns of the view with this name in the config file")
+                    .long("view")
+                    .value_name("name")
+                    .takes_value(true)
+                    .conflicts_with("long")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("watch")
+                .about("Show codes of all records, refreshed every second until a key is pressed")
+                .arg(Arg::with_name("timeout")
+                    .help("stop after this many seconds")
+                    .long("timeout")
+                    .value_name("seconds")
+                    .takes_value(true)
+                )
+                .arg(Arg::with_name("long")
+                    .help("also show ID, tags, last use and notes")
+                    .long("long")
+                    .short("l")
+                )
+                .arg(Arg::with_name("columns")
+                    .help("comma separated columns of the table, each optionally followed by :left, :middle or :right. \
+                           Columns are index, name, issuer, account, id, tags, notes, previous, current, next, \
+                           seconds-left, period, algorithm and last-used")
+                    .long("columns")
+                    .value_name("columns")
+                    .takes_value(true)
+                    .conflicts_with_all(&["long", "view"])
+                )
+                .arg(Arg::with_name("view")
+                    .help("columns of the view with this name in the config file")
+                    .long("view")
+                    .value_name("name")
+                    .takes_value(true)
+                    .conflicts_with("long")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("browse")
+                .about("Full screen list of records with live codes, search and editing")
+                .arg(Arg::with_name("idle")
+                    .help("seconds without a key after which records are locked and the password is asked again, 0 never")
+                    .long("idle")
+                    .value_name("seconds")
+                    .takes_value(true)
+                    .default_value("300")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("get")
+                .about("Print current code of one record")
+                .arg(Arg::with_name("record")
+                    .help("index, name, part of the name or id: of the record")
+                    .index(1)
+                    .takes_value(true)
+                    .required(true)
+                )
+                .arg(Arg::with_name("copy")
+                    .help("put the code to the clipboard of the terminal instead of printing it, cleared when the code expires")
+                    .short("c")
+                    .long("copy")
+                )
+                .arg(Arg::with_name("min-left")
+                    .help("refuse to copy a code valid for fewer seconds")
+                    .long("min-left")
+                    .value_name("seconds")
+                    .takes_value(true)
+                    .default_value("5")
+                )
+                .arg(Arg::with_name("force")
+                    .help("copy the code however soon it expires")
+                    .long("force")
+                    .requires("copy")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("next")
+                .about("Print next code of HOTP record and advance its counter")
+                .arg(Arg::with_name("record")
+                    .help("index, name, part of the name or id: of the record")
+                    .index(1)
+                    .takes_value(true)
+                    .required(true)
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("resync")
+                .about("Set HOTP counter after two consecutive codes generated by the token")
+                .arg(Arg::with_name("record")
+                    .help("index, name, part of the name or id: of the record")
+                    .index(1)
+                    .takes_value(true)
+                    .required(true)
+                )
+                .arg(Arg::with_name("code1")
+                    .help("first code")
+                    .index(2)
+                    .takes_value(true)
+                    .required(true)
+                )
+                .arg(Arg::with_name("code2")
+                    .help("code following the first one")
+                    .index(3)
+                    .takes_value(true)
+                    .required(true)
+                )
+                .arg(Arg::with_name("window")
+                    .help("how many counter values ahead of the stored one to search")
+                    .long("window")
+                    .takes_value(true)
+                    .default_value("100")
+                )
         )
         .subcommand(
             SubCommand::with_name("recrypt")
-                .about("Re-encrypt file")
+                .about("Re-encrypt file with new password")
                 // or "myapp help"
                 .arg(Arg::with_name("newpass")
-                    .help("new password")
+                    .help("new password, visible to other users in ps output. Asked twice on the terminal when omitted")
                     .index(1)
                     .takes_value(true)
-                    .required(true)
+                )
+                .arg(Arg::with_name("new-password-fd")
+                    .help("read new password from the first line of file descriptor <n>, other than --password-fd. \
+                           TOTPKEEP_NEW_PASSWORD_FILE environment variable can name a file to read it from instead")
+                    .long("new-password-fd")
+                    .value_name("n")
+                    .takes_value(true)
+                    .conflicts_with("newpass")
+                )
+                .arg(Arg::with_name("kdf")
+                    .help("key derivation function. File keeps its current one when omitted")
+                    .long("kdf")
+                    .takes_value(true)
+                    .possible_values(&["bcrypt-pbkdf", "argon2id"])
+                )
+                .arg(Arg::with_name("cost")
+                    .help("bcrypt_pbkdf cost. Default is 16")
+                    .long("cost")
+                    .takes_value(true)
+                    .requires("kdf")
+                )
+                .arg(Arg::with_name("memory")
+                    .help("Argon2id memory in KiB. Default is 65536")
+                    .long("memory")
+                    .takes_value(true)
+                    .requires("kdf")
+                )
+                .arg(Arg::with_name("iterations")
+                    .help("Argon2id number of iterations. Default is 3")
+                    .long("iterations")
+                    .takes_value(true)
+                    .requires("kdf")
+                )
+                .arg(Arg::with_name("parallelism")
+                    .help("Argon2id number of lanes. Default is 4")
+                    .long("parallelism")
+                    .takes_value(true)
+                    .requires("kdf")
                 )
         )
+        .subcommand(
+            SubCommand::with_name("restore")
+                .about("List backups of the file or restore one of them")
+                .arg(Arg::with_name("generation")
+                    .help("backup number as shown by \"totpkeep restore\". Backups are listed when omitted")
+                    .index(1)
+                    .takes_value(true)
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("check")
+                .about("Report every record of the file that can't be read")
+                .arg(Arg::with_name("salvage")
+                    .help("write the records that can be read to a new file, with the same password")
+                    .long("salvage")
+                    .value_name("file")
+                    .takes_value(true)
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("agent")
+                .about("Start agent caching derived keys of unlocked files")
+                .arg(Arg::with_name("timeout")
+                    .help("seconds without requests after which keys are forgotten, 0 to keep them until \"lock\"")
+                    .long("timeout")
+                    .takes_value(true)
+                    .default_value("900")
+                )
+                .arg(Arg::with_name("socket")
+                    .help("socket path. Default is $XDG_RUNTIME_DIR/totpkeep-agent.sock")
+                    .long("socket")
+                    .takes_value(true)
+                )
+                .arg(Arg::with_name("foreground")
+                    .help("do not detach from the terminal")
+                    .long("foreground")
+                    .short("d")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("unlock")
+                .about("Add key of the file to the agent set in TOTPKEEP_AGENT_SOCK")
+        )
+        .subcommand(
+            SubCommand::with_name("lock")
+                .about("Make the agent forget all keys")
+        )
         .get_matches();
 
-    let password = matches.value_of("password").unwrap();
+    let password_fd = match matches.value_of("password-fd") {
+        Some(_) => Some(value_t_or_exit!(matches, "password-fd", i32)),
+        None => None
+    };
+    let password = &password::Password::new(matches.value_of("password"), password_fd);
     let file = matches.value_of("file");
     let symbols: &table::TableSymbols = match matches.is_present("ascii") {
         true => &table::AsciiTableSymbols{},
         false => &table::UnicodeTableSymbols{}
     };
     let rslt = match matches.subcommand() {
+        ("add", Some(m)) if m.is_present("qr") => {
+            qr::decode_image(m.value_of("qr").unwrap()).and_then(|uris| {
+                let uris = uris.iter().map(|u| &u[..]).collect::<Vec<&str>>();
+                totpkeep::import_uris(&uris, password, file, symbols)
+            })
+        },
         ("add", Some(m)) => {
             let name = m.value_of("name").unwrap();
             let code = m.value_of("secret").unwrap();
-            totpkeep::add_service(name, code, password, file, symbols)
+            totpkeep::add_service(name, code, otp_params(m), otp_kind(m), &record_changes(m), password, file, symbols)
+        },
+        ("import-uri", Some(m)) => {
+            let uris = m.values_of("uri").unwrap().collect::<Vec<&str>>();
+            totpkeep::import_uris(&uris, password, file, symbols)
+        },
+        ("export-uri", Some(m)) => {
+            totpkeep::export_uris(m.value_of("record"), m.is_present("google"), password, file)
+        },
+        ("import", Some(m)) => {
+            let path = m.value_of("path").unwrap();
+            totpkeep::import_aegis(path, m.value_of("backup-password"), password, file, symbols)
+        },
+        ("export", Some(m)) => {
+            let path = m.value_of("path").unwrap();
+            match m.is_present("encrypt") {
+                true => password::confirm_password(m.value_of("backup-password"), "backup password")
+                    .and_then(|backup| totpkeep::export_aegis(path, Some(&backup), password, file)),
+                false => totpkeep::export_aegis(path, None, password, file)
+            }
+        },
+        ("show-qr", Some(m)) => {
+            let record = m.value_of("record").unwrap();
+            let ascii = matches.is_present("ascii");
+            totpkeep::show_qr(record, m.value_of("output"), ascii, m.is_present("invert"), password, file)
+        },
+        ("edit", Some(m)) => {
+            totpkeep::edit_service(m.value_of("record").unwrap(), &record_changes(m), password, file, symbols)
         },
         ("remove", Some(m)) => {
-            let index = m.value_of("index").unwrap().parse::<u16>().unwrap();
-            totpkeep::remove_service(index, password, file, symbols)
+            totpkeep::remove_service(m.value_of("record").unwrap(), password, file, symbols)
+        },
+        ("list", Some(m)) => {
+            let format = match m.value_of("format").unwrap() {
+                "json" => ListFormat::Json,
+                "tsv" => ListFormat::Tsv,
+                "plain" => ListFormat::Plain,
+                _ => ListFormat::Table
+            };
+            view_columns(m).and_then(|columns| totpkeep::list_services(format, &columns, password, file, symbols))
+        },
+        ("watch", Some(m)) => {
+            let timeout = match m.value_of("timeout") {
+                Some(_) => Some(value_t_or_exit!(m, "timeout", u64)),
+                None => None
+            };
+            view_columns(m).and_then(|columns| totpkeep::watch(timeout, &columns, password, file, symbols))
+        },
+        ("browse", Some(m)) => {
+            let idle = value_t_or_exit!(m, "idle", u64);
+            totpkeep::browse(idle, password, file, symbols)
+        },
+        ("get", Some(m)) => {
+            let min_left = value_t_or_exit!(m, "min-left", u64);
+            let copy = match (m.is_present("copy"), m.is_present("force")) {
+                (false, _) => None,
+                (true, false) => Some(min_left),
+                (true, true) => Some(0)
+            };
+            totpkeep::get_code(m.value_of("record").unwrap(), copy, password, file)
+        },
+        ("next", Some(m)) => totpkeep::next_hotp(m.value_of("record").unwrap(), password, file),
+        ("resync", Some(m)) => {
+            let code1 = m.value_of("code1").unwrap();
+            let code2 = m.value_of("code2").unwrap();
+            let window = value_t_or_exit!(m, "window", u64);
+            totpkeep::resync_hotp(m.value_of("record").unwrap(), code1, code2, window, password, file)
+        },
+        ("recrypt", Some(m)) => {
+            let new_password_fd = match m.value_of("new-password-fd") {
+                Some(_) => Some(value_t_or_exit!(m, "new-password-fd", i32)),
+                None => None
+            };
+            password.get()
+                .and_then(|_| password::read_new_password(m.value_of("newpass"), new_password_fd))
+                .and_then(|newpass| totpkeep::change_password(password, &newpass, kdf(m), file))
+        },
+        ("agent", Some(m)) => {
+            let timeout = value_t_or_exit!(m, "timeout", u64);
+            agent::run(m.value_of("socket"), timeout, m.is_present("foreground"))
+        },
+        ("restore", Some(m)) => match m.value_of("generation") {
+            Some(_) => totpkeep::restore_backup(value_t_or_exit!(m, "generation", usize), password, file),
+            None => totpkeep::list_backups(file)
         },
-        ("list", Some(m)) => totpkeep::list_services(password, file, symbols),
-        ("recrypt", Some(m)) => Ok(()),
+        ("check", Some(m)) => totpkeep::check(m.value_of("salvage"), password, file),
+        ("unlock", Some(_)) => totpkeep::unlock(password, file),
+        ("lock", Some(_)) => totpkeep::lock(),
         (&_, _) => Err(Error::UnknownCommand)
     };
-    match rslt {
-        Ok(_) => (),
-        Err(err) => println!("Error: {}", err)
+    // scripts take stdout as the result, failures must not end up there
+    if let Err(err) = rslt {
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
     }
 }
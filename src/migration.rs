@@ -0,0 +1,336 @@
+use base64;
+use rand::{OsRng, Rng};
+use errors::{Error};
+use otpauth::{percent_decode, percent_encode};
+use totpkeep::{Algorithm, OtpKind, OtpParams};
+use utils::{read_varint, write_varint};
+
+const SCHEME: &str = "otpauth-migration://offline?";
+/// Google Authenticator shows at most this many accounts in a single QR code.
+const BATCH_SIZE: usize = 10;
+
+const WIRE_VARINT: u64 = 0;
+const WIRE_FIXED64: u64 = 1;
+const WIRE_LENGTH_DELIMITED: u64 = 2;
+const WIRE_FIXED32: u64 = 5;
+
+// MigrationPayload.otp_parameters and the batch fields
+const PAYLOAD_OTP_PARAMETERS: u64 = 1;
+const PAYLOAD_VERSION: u64 = 2;
+const PAYLOAD_BATCH_SIZE: u64 = 3;
+const PAYLOAD_BATCH_INDEX: u64 = 4;
+const PAYLOAD_BATCH_ID: u64 = 5;
+
+// OtpParameters fields
+const OTP_SECRET: u64 = 1;
+const OTP_NAME: u64 = 2;
+const OTP_ISSUER: u64 = 3;
+const OTP_ALGORITHM: u64 = 4;
+const OTP_DIGITS: u64 = 5;
+const OTP_TYPE: u64 = 6;
+const OTP_COUNTER: u64 = 7;
+
+const ALGORITHM_SHA1: u64 = 1;
+const ALGORITHM_SHA256: u64 = 2;
+const ALGORITHM_SHA512: u64 = 3;
+const DIGITS_SIX: u64 = 1;
+const DIGITS_EIGHT: u64 = 2;
+const TYPE_HOTP: u64 = 1;
+const TYPE_TOTP: u64 = 2;
+
+/// Single account of Google Authenticator "Transfer accounts" export.
+/// The format has no period and T0, they are always 30 and 0.
+#[derive(Debug)]
+pub struct MigrationAccount {
+    pub name: String,
+    pub issuer: Option<String>,
+    pub secret: Vec<u8>,
+    pub params: OtpParams,
+    pub kind: OtpKind
+}
+
+impl MigrationAccount {
+    /// Whether the account can be moved to Google Authenticator without changing its codes.
+    pub fn is_exportable(params: &OtpParams) -> bool {
+        params.period == 30 && params.epoch == 0 && (params.digits == 6 || params.digits == 8)
+    }
+}
+
+fn read_number(data: &[u8], pos: &mut usize) -> Result<u64, Error> {
+    read_varint(data, pos).ok_or(Error::WrongMigrationPayload)
+}
+
+fn read_bytes<'a>(data: &'a [u8], pos: &mut usize) -> Result<&'a [u8], Error> {
+    let len = read_number(data, pos)? as usize;
+    let start = *pos;
+    if len > data.len() - start {
+        return Err(Error::WrongMigrationPayload)
+    }
+    *pos += len;
+    Ok(&data[start..start + len])
+}
+
+enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed
+}
+
+/// Reads protobuf message fields as (field number, value) pairs.
+fn read_fields(data: &[u8]) -> Result<Vec<(u64, Value)>, Error> {
+    let mut fields = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let key = read_number(data, &mut pos)?;
+        let value = match key & 7 {
+            WIRE_VARINT => Value::Varint(read_number(data, &mut pos)?),
+            WIRE_LENGTH_DELIMITED => Value::Bytes(read_bytes(data, &mut pos)?),
+            WIRE_FIXED64 | WIRE_FIXED32 => {
+                pos += if key & 7 == WIRE_FIXED64 { 8 } else { 4 };
+                if pos > data.len() {
+                    return Err(Error::WrongMigrationPayload)
+                }
+                Value::Fixed
+            },
+            _ => return Err(Error::WrongMigrationPayload)
+        };
+        fields.push((key >> 3, value));
+    }
+    Ok(fields)
+}
+
+fn to_string(bytes: &[u8]) -> Result<String, Error> {
+    String::from_utf8(bytes.to_vec()).map_err(|_| Error::WrongMigrationPayload)
+}
+
+fn parse_account(data: &[u8]) -> Result<MigrationAccount, Error> {
+    let mut name = String::new();
+    let mut issuer = String::new();
+    let mut secret = Vec::new();
+    let mut params = OtpParams::default();
+    let mut hotp = false;
+    let mut counter = 0;
+    for (field, value) in read_fields(data)? {
+        match (field, value) {
+            (OTP_SECRET, Value::Bytes(b)) => secret = b.to_vec(),
+            (OTP_NAME, Value::Bytes(b)) => name = to_string(b)?,
+            (OTP_ISSUER, Value::Bytes(b)) => issuer = to_string(b)?,
+            (OTP_ALGORITHM, Value::Varint(v)) => params.algorithm = match v {
+                // unspecified means the default
+                0 | ALGORITHM_SHA1 => Algorithm::SHA1,
+                ALGORITHM_SHA256 => Algorithm::SHA256,
+                ALGORITHM_SHA512 => Algorithm::SHA512,
+                _ => return Err(Error::WrongServiceRecordParameters)
+            },
+            (OTP_DIGITS, Value::Varint(v)) => params.digits = match v {
+                0 | DIGITS_SIX => 6,
+                DIGITS_EIGHT => 8,
+                _ => return Err(Error::WrongServiceRecordParameters)
+            },
+            (OTP_TYPE, Value::Varint(v)) => hotp = v == TYPE_HOTP,
+            (OTP_COUNTER, Value::Varint(v)) => counter = v,
+            _ => ()
+        }
+    }
+    if secret.is_empty() {
+        return Err(Error::WrongMigrationPayload)
+    }
+    // name usually repeats the issuer as "Issuer:account"
+    let prefix = format!("{}:", issuer);
+    if !issuer.is_empty() && name.starts_with(&prefix) {
+        name = name[prefix.len()..].trim_left().to_string();
+    }
+    Ok(MigrationAccount {
+        name,
+        issuer: if issuer.is_empty() { None } else { Some(issuer) },
+        secret,
+        params,
+        kind: if hotp { OtpKind::Hotp { counter } } else { OtpKind::Totp }
+    })
+}
+
+/// Decodes all accounts of `otpauth-migration://offline?data=...` URI.
+pub fn parse(uri: &str) -> Result<Vec<MigrationAccount>, Error> {
+    let uri = uri.trim();
+    match uri.get(..SCHEME.len()) {
+        Some(scheme) if scheme.to_lowercase() == SCHEME => (),
+        _ => return Err(Error::WrongUri)
+    }
+    let data = uri[SCHEME.len()..].split('&')
+        .filter_map(|pair| match pair.find('=') {
+            Some(pos) if &pair[..pos] == "data" => Some(&pair[pos + 1..]),
+            _ => None
+        })
+        .next()
+        .ok_or(Error::WrongUri)?;
+    // '+' is a part of base64 alphabet rather than encoded space here
+    let payload = base64::decode(&percent_decode(data, false)?)
+        .map_err(|_| Error::WrongMigrationPayload)?;
+    let mut accounts = Vec::new();
+    for (field, value) in read_fields(&payload)? {
+        if let (PAYLOAD_OTP_PARAMETERS, Value::Bytes(b)) = (field, value) {
+            accounts.push(parse_account(b)?);
+        }
+    }
+    Ok(accounts)
+}
+
+fn write_varint_field(out: &mut Vec<u8>, field: u64, value: u64) {
+    write_varint(out, field << 3 | WIRE_VARINT);
+    write_varint(out, value);
+}
+
+fn write_bytes_field(out: &mut Vec<u8>, field: u64, value: &[u8]) {
+    write_varint(out, field << 3 | WIRE_LENGTH_DELIMITED);
+    write_varint(out, value.len() as u64);
+    out.extend_from_slice(value);
+}
+
+fn write_account(account: &MigrationAccount) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_bytes_field(&mut out, OTP_SECRET, &account.secret);
+    let name = match account.issuer {
+        Some(ref issuer) => format!("{}:{}", issuer, account.name),
+        None => account.name.clone()
+    };
+    write_bytes_field(&mut out, OTP_NAME, name.as_bytes());
+    if let Some(ref issuer) = account.issuer {
+        write_bytes_field(&mut out, OTP_ISSUER, issuer.as_bytes());
+    }
+    write_varint_field(&mut out, OTP_ALGORITHM, match account.params.algorithm {
+        Algorithm::SHA1 => ALGORITHM_SHA1,
+        Algorithm::SHA256 => ALGORITHM_SHA256,
+        Algorithm::SHA512 => ALGORITHM_SHA512
+    });
+    write_varint_field(&mut out, OTP_DIGITS, if account.params.digits == 8 { DIGITS_EIGHT } else { DIGITS_SIX });
+    match account.kind {
+        OtpKind::Totp => write_varint_field(&mut out, OTP_TYPE, TYPE_TOTP),
+        OtpKind::Hotp { counter } => {
+            write_varint_field(&mut out, OTP_TYPE, TYPE_HOTP);
+            write_varint_field(&mut out, OTP_COUNTER, counter);
+        }
+    }
+    out
+}
+
+/// Encodes accounts into URIs of at most `BATCH_SIZE` accounts each, so every one fits in a QR code.
+/// Accounts must be checked with `MigrationAccount::is_exportable` first.
+pub fn to_uris(accounts: &[MigrationAccount]) -> Result<Vec<String>, Error> {
+    let batch_id = OsRng::new()?.next_u32() >> 1;
+    let chunks = accounts.chunks(BATCH_SIZE).collect::<Vec<_>>();
+    let mut uris = Vec::new();
+    for (index, chunk) in chunks.iter().enumerate() {
+        let mut payload = Vec::new();
+        for account in chunk.iter() {
+            write_bytes_field(&mut payload, PAYLOAD_OTP_PARAMETERS, &write_account(account));
+        }
+        write_varint_field(&mut payload, PAYLOAD_VERSION, 1);
+        write_varint_field(&mut payload, PAYLOAD_BATCH_SIZE, chunks.len() as u64);
+        write_varint_field(&mut payload, PAYLOAD_BATCH_INDEX, index as u64);
+        write_varint_field(&mut payload, PAYLOAD_BATCH_ID, batch_id as u64);
+        uris.push(format!("{}data={}", SCHEME, percent_encode(&base64::encode(&payload))));
+    }
+    Ok(uris)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account(name: &str, issuer: Option<&str>, kind: OtpKind) -> MigrationAccount {
+        MigrationAccount {
+            name: name.to_string(),
+            issuer: issuer.map(|i| i.to_string()),
+            secret: b"Hello!\xde\xad\xbe\xef".to_vec(),
+            params: OtpParams { digits: 8, period: 30, algorithm: Algorithm::SHA256, epoch: 0 },
+            kind
+        }
+    }
+
+    fn uri(payload: &[u8]) -> String {
+        format!("{}data={}", SCHEME, percent_encode(&base64::encode(payload)))
+    }
+
+    #[test]
+    fn varint() {
+        for &value in &[0, 1, 127, 128, 300, u32::max_value() as u64, u64::max_value()] {
+            let mut out = Vec::new();
+            write_varint(&mut out, value);
+            let mut pos = 0;
+            assert_eq!(read_varint(&out, &mut pos).unwrap(), value);
+            assert_eq!(pos, out.len());
+        }
+        let mut pos = 0;
+        assert!(read_number(&[0x80, 0x80], &mut pos).is_err());
+    }
+
+    #[test]
+    fn parse_payload() {
+        // as Google Authenticator writes it, with a fixed32 field unknown to the reader
+        let mut params = vec![0x0a, 10];
+        params.extend_from_slice(b"Hello!\xde\xad\xbe\xef");
+        params.extend_from_slice(&[0x12, 24]);
+        params.extend_from_slice(b"Example:alice@google.com");
+        params.extend_from_slice(&[0x1a, 7]);
+        params.extend_from_slice(b"Example");
+        params.extend_from_slice(&[0x20, 1, 0x28, 1, 0x30, 2, 0x7d, 1, 2, 3, 4]);
+        let mut payload = vec![0x0a, params.len() as u8];
+        payload.extend_from_slice(&params);
+        payload.extend_from_slice(&[0x10, 1, 0x18, 1, 0x20, 0, 0x28, 0x2a]);
+
+        let accounts = parse(&uri(&payload)).unwrap();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].name, "alice@google.com");
+        assert_eq!(accounts[0].issuer, Some(String::from("Example")));
+        assert_eq!(accounts[0].secret, b"Hello!\xde\xad\xbe\xef".to_vec());
+        assert_eq!(accounts[0].params, OtpParams::default());
+        assert_eq!(accounts[0].kind, OtpKind::Totp);
+    }
+
+    #[test]
+    fn round_trip() {
+        let accounts = vec![
+            account("alice", Some("Example"), OtpKind::Totp),
+            account("bob", None, OtpKind::Hotp { counter: 300 })
+        ];
+        let uris = to_uris(&accounts).unwrap();
+        assert_eq!(uris.len(), 1);
+        let parsed = parse(&uris[0]).unwrap();
+        assert_eq!(parsed.len(), 2);
+        for (parsed, account) in parsed.iter().zip(accounts.iter()) {
+            assert_eq!(parsed.name, account.name);
+            assert_eq!(parsed.issuer, account.issuer);
+            assert_eq!(parsed.secret, account.secret);
+            assert_eq!(parsed.params, account.params);
+            assert_eq!(parsed.kind, account.kind);
+        }
+    }
+
+    #[test]
+    fn batches() {
+        let accounts = (0..BATCH_SIZE + 1)
+            .map(|i| account(&format!("user{}", i), None, OtpKind::Totp))
+            .collect::<Vec<_>>();
+        let uris = to_uris(&accounts).unwrap();
+        assert_eq!(uris.len(), 2);
+        assert_eq!(parse(&uris[0]).unwrap().len(), BATCH_SIZE);
+        assert_eq!(parse(&uris[1]).unwrap()[0].name, format!("user{}", BATCH_SIZE));
+    }
+
+    #[test]
+    fn malformed() {
+        let mut valid = Vec::new();
+        write_bytes_field(&mut valid, PAYLOAD_OTP_PARAMETERS, &write_account(&account("a", None, OtpKind::Totp)));
+        assert!(parse(&uri(&valid)).is_ok());
+        for cut in 1..valid.len() {
+            assert!(parse(&uri(&valid[..cut])).is_err(), "payload cut at {} was accepted", cut);
+        }
+        assert!(parse("otpauth://offline?data=AA").is_err());
+        assert!(parse("otpauth-migration://offline?other=AA").is_err());
+        assert!(parse("otpauth-migration://offline?data=%%%").is_err());
+        // account without secret
+        let mut payload = Vec::new();
+        write_bytes_field(&mut payload, PAYLOAD_OTP_PARAMETERS, &[0x12, 1, b'a']);
+        assert!(parse(&uri(&payload)).is_err());
+    }
+}
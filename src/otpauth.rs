@@ -0,0 +1,240 @@
+use std::fmt;
+use errors::{Error};
+use totpkeep::{Algorithm, OtpKind, OtpParams};
+
+const SCHEME: &str = "otpauth://";
+
+/// Key URI as produced by enrollment pages:
+/// `otpauth://totp/Issuer:account?secret=...&issuer=...&digits=...&period=...&algorithm=...`
+/// or `otpauth://hotp/...&counter=...`
+#[derive(Debug)]
+pub struct OtpAuthUri {
+    pub issuer: Option<String>,
+    pub account: String,
+    pub secret: String,
+    pub params: OtpParams,
+    pub kind: OtpKind
+}
+
+impl OtpAuthUri {
+    pub fn parse(uri: &str) -> Result<OtpAuthUri, Error> {
+        let uri = uri.trim();
+        match uri.get(..SCHEME.len()) {
+            Some(scheme) if scheme.to_lowercase() == SCHEME => (),
+            _ => return Err(Error::WrongUri)
+        }
+        let rest = &uri[SCHEME.len()..];
+        let (kind, rest) = match rest.find('/') {
+            Some(pos) => (&rest[..pos], &rest[pos + 1..]),
+            None => return Err(Error::WrongUri)
+        };
+        let hotp = match &kind.to_lowercase()[..] {
+            "totp" => false,
+            "hotp" => true,
+            _ => return Err(Error::WrongUri)
+        };
+        let (label, query) = match rest.find('?') {
+            Some(pos) => (&rest[..pos], &rest[pos + 1..]),
+            None => (rest, "")
+        };
+
+        // literal colon separates the issuer, so that an encoded one may be part of it;
+        // some generators encode the separator too
+        let (mut issuer, account) = match label.find(':') {
+            Some(pos) => (Some(percent_decode(&label[..pos], false)?), percent_decode(&label[pos + 1..], false)?),
+            None => {
+                let label = percent_decode(label, false)?;
+                match label.find(':') {
+                    Some(pos) => (Some(label[..pos].to_string()), label[pos + 1..].to_string()),
+                    None => (None, label)
+                }
+            }
+        };
+        let account = account.trim_left().to_string();
+
+        let mut secret = None;
+        let mut params = OtpParams::default();
+        let mut counter = None;
+        for pair in query.split('&').filter(|p| !p.is_empty()) {
+            let (key, value) = match pair.find('=') {
+                Some(pos) => (&pair[..pos], percent_decode(&pair[pos + 1..], true)?),
+                None => (pair, String::new())
+            };
+            match &key.to_lowercase()[..] {
+                "secret" => secret = Some(value),
+                // issuer parameter is preferred over the label prefix
+                "issuer" => issuer = Some(value),
+                "digits" => params.digits = value.parse().map_err(|_| Error::WrongUri)?,
+                "period" => params.period = value.parse().map_err(|_| Error::WrongUri)?,
+                "algorithm" => params.algorithm = Algorithm::parse(&value)?,
+                "counter" => counter = Some(value.parse().map_err(|_| Error::WrongUri)?),
+                _ => ()
+            }
+        }
+        let secret = match secret {
+            Some(s) => s,
+            None => return Err(Error::WrongUri)
+        };
+        params.validate()?;
+        let kind = match (hotp, counter) {
+            (false, _) => OtpKind::Totp,
+            (true, Some(counter)) => OtpKind::Hotp { counter },
+            // counter is required for HOTP by the key URI format
+            (true, None) => return Err(Error::WrongUri)
+        };
+        Ok(OtpAuthUri {
+            issuer: issuer.and_then(|i| if i.is_empty() { None } else { Some(i) }),
+            account,
+            secret,
+            params,
+            kind
+        })
+    }
+}
+
+impl fmt::Display for OtpAuthUri {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(SCHEME)?;
+        f.write_str(match self.kind {
+            OtpKind::Totp => "totp/",
+            OtpKind::Hotp { .. } => "hotp/"
+        })?;
+        if let Some(ref issuer) = self.issuer {
+            write!(f, "{}:", percent_encode(issuer))?;
+        }
+        write!(f, "{}?secret={}", percent_encode(&self.account), self.secret)?;
+        if let Some(ref issuer) = self.issuer {
+            write!(f, "&issuer={}", percent_encode(issuer))?;
+        }
+        match self.kind {
+            OtpKind::Totp => write!(f, "&algorithm={}&digits={}&period={}",
+                                    self.params.algorithm.name(), self.params.digits, self.params.period),
+            OtpKind::Hotp { counter } => write!(f, "&algorithm={}&digits={}&counter={}",
+                                                self.params.algorithm.name(), self.params.digits, counter)
+        }
+    }
+}
+
+pub fn percent_decode(s: &str, plus_as_space: bool) -> Result<String, Error> {
+    let bytes = s.as_bytes();
+    let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                if i + 2 >= bytes.len() {
+                    return Err(Error::WrongUri)
+                }
+                let hex = ::std::str::from_utf8(&bytes[i + 1..i + 3]).map_err(|_| Error::WrongUri)?;
+                decoded.push(u8::from_str_radix(hex, 16).map_err(|_| Error::WrongUri)?);
+                i += 3;
+            },
+            b'+' if plus_as_space => {
+                decoded.push(b' ');
+                i += 1;
+            },
+            b => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| Error::WrongUri)
+}
+
+pub fn percent_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for &b in s.as_bytes() {
+        match b {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'-' | b'.' | b'_' | b'~' | b'@' =>
+                encoded.push(b as char),
+            _ => encoded.push_str(&format!("%{:02X}", b))
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_totp() {
+        let uri = OtpAuthUri::parse("otpauth://totp/ACME%20Co:john.doe@email.com?secret=HXDMVJECJJWSRB3HWIZR4IFUGFTMXBOZ\
+                                     &issuer=ACME+Co&algorithm=SHA256&digits=8&period=60").unwrap();
+        assert_eq!(uri.issuer, Some(String::from("ACME Co")));
+        assert_eq!(uri.account, "john.doe@email.com");
+        assert_eq!(uri.secret, "HXDMVJECJJWSRB3HWIZR4IFUGFTMXBOZ");
+        assert_eq!(uri.params, OtpParams { digits: 8, period: 60, algorithm: Algorithm::SHA256, epoch: 0 });
+        assert_eq!(uri.kind, OtpKind::Totp);
+    }
+
+    #[test]
+    fn issuer_parameter_is_preferred_over_label() {
+        let uri = OtpAuthUri::parse("otpauth://totp/Label:%20john?secret=ABC&issuer=Param").unwrap();
+        assert_eq!(uri.issuer, Some(String::from("Param")));
+        assert_eq!(uri.account, "john");
+        let uri = OtpAuthUri::parse("otpauth://totp/ACME%3Ajohn?secret=ABC").unwrap();
+        assert_eq!(uri.issuer, Some(String::from("ACME")));
+        assert_eq!(uri.account, "john");
+        let uri = OtpAuthUri::parse("OTPAUTH://TOTP/john?secret=ABC").unwrap();
+        assert_eq!(uri.issuer, None);
+        assert_eq!(uri.params, OtpParams::default());
+    }
+
+    #[test]
+    fn parse_hotp() {
+        let uri = OtpAuthUri::parse("otpauth://hotp/Yubi:key?secret=ABC&counter=42").unwrap();
+        assert_eq!(uri.kind, OtpKind::Hotp { counter: 42 });
+        assert!(OtpAuthUri::parse("otpauth://hotp/Yubi:key?secret=ABC").is_err());
+    }
+
+    #[test]
+    fn parse_refuses_malformed() {
+        for uri in &["https://totp/a?secret=ABC", "otpauth://totp", "otpauth://motp/a?secret=ABC",
+                     "otpauth://totp/a", "otpauth://totp/a?secret=ABC&digits=x", "otpauth://totp/a?secret=ABC&digits=5",
+                     "otpauth://totp/a?secret=ABC&period=0", "otpauth://totp/a?secret=ABC&algorithm=MD5",
+                     "otpauth://totp/a%2?secret=ABC", "otpauth://totp/a%ff?secret=ABC"] {
+            assert!(OtpAuthUri::parse(uri).is_err(), "{} was accepted", uri);
+        }
+    }
+
+    #[test]
+    fn display_round_trip() {
+        let uri = OtpAuthUri {
+            issuer: Some(String::from("Ünïcode & Co: 100%")),
+            account: String::from("john+tag@example.com/x?y=z"),
+            secret: String::from("JBSWY3DPEHPK3PXP"),
+            params: OtpParams { digits: 7, period: 45, algorithm: Algorithm::SHA512, epoch: 0 },
+            kind: OtpKind::Totp
+        };
+        let text = uri.to_string();
+        assert_eq!(text, "otpauth://totp/%C3%9Cn%C3%AFcode%20%26%20Co%3A%20100%25:\
+                          john%2Btag@example.com%2Fx%3Fy%3Dz?secret=JBSWY3DPEHPK3PXP\
+                          &issuer=%C3%9Cn%C3%AFcode%20%26%20Co%3A%20100%25&algorithm=SHA512&digits=7&period=45");
+        let parsed = OtpAuthUri::parse(&text).unwrap();
+        assert_eq!(parsed.issuer, uri.issuer);
+        assert_eq!(parsed.account, uri.account);
+        assert_eq!(parsed.secret, uri.secret);
+        assert_eq!(parsed.params, uri.params);
+
+        let hotp = OtpAuthUri { kind: OtpKind::Hotp { counter: 7 }, issuer: None, ..parsed };
+        assert_eq!(hotp.to_string(),
+                   "otpauth://hotp/john%2Btag@example.com%2Fx%3Fy%3Dz?secret=JBSWY3DPEHPK3PXP&algorithm=SHA512&digits=7&counter=7");
+        assert_eq!(OtpAuthUri::parse(&hotp.to_string()).unwrap().kind, OtpKind::Hotp { counter: 7 });
+    }
+
+    #[test]
+    fn percent_coding() {
+        assert_eq!(percent_encode("a-b.c_d~e@f"), "a-b.c_d~e@f");
+        assert_eq!(percent_encode("a b+c"), "a%20b%2Bc");
+        assert_eq!(percent_encode("é"), "%C3%A9");
+        assert_eq!(percent_decode("a%20b+c", false).unwrap(), "a b+c");
+        assert_eq!(percent_decode("a%20b+c", true).unwrap(), "a b c");
+        assert_eq!(percent_decode("%c3%a9", false).unwrap(), "é");
+        assert!(percent_decode("%", false).is_err());
+        assert!(percent_decode("%4", false).is_err());
+        assert!(percent_decode("%zz", false).is_err());
+        assert!(percent_decode("%C3", false).is_err());
+    }
+}
@@ -0,0 +1,134 @@
+use std::cell::RefCell;
+use std::env;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use errors::{Error};
+use rpassword::read_password_from_tty;
+
+/// Environment variable with path to the file holding the password in its first line.
+pub const PASSWORD_FILE_ENV: &str = "TOTPKEEP_PASSWORD_FILE";
+/// The same for the new password of `recrypt`.
+pub const NEW_PASSWORD_FILE_ENV: &str = "TOTPKEEP_NEW_PASSWORD_FILE";
+
+fn prompt(text: &str) -> Result<String, Error> {
+    read_password_from_tty(Some(text)).map_err(|_| Error::PasswordPromptFailed)
+}
+
+fn read_first_line<R: BufRead>(mut source: R) -> Result<String, Error> {
+    let mut line = String::new();
+    source.read_line(&mut line).map_err(|_| Error::PasswordSourceFailed)?;
+    let len = line.trim_right_matches(|c| c == '\n' || c == '\r').len();
+    line.truncate(len);
+    Ok(line)
+}
+
+/// The descriptor belongs to the caller and stays open, e.g. stdin for later confirmations.
+/// It is read a byte at a time, so nothing after the password line is taken from it.
+#[cfg(unix)]
+fn read_from_fd(fd: i32) -> Result<String, Error> {
+    use std::mem::ManuallyDrop;
+    use std::os::unix::io::FromRawFd;
+    let file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+    read_first_line(BufReader::with_capacity(1, &*file))
+}
+
+#[cfg(not(unix))]
+fn read_from_fd(_fd: i32) -> Result<String, Error> {
+    Err(Error::PasswordSourceFailed)
+}
+
+/// Password given without the terminal: argument, descriptor or file named by `file_env`.
+/// None when none of them is given.
+fn read_given(arg: Option<&str>, fd: Option<i32>, file_env: &str) -> Result<Option<String>, Error> {
+    match (arg, fd, env::var_os(file_env)) {
+        (Some(p), _, _) => Ok(Some(p.to_string())),
+        (None, Some(fd), _) => read_from_fd(fd).map(Some),
+        (None, None, Some(path)) =>
+            read_first_line(BufReader::new(File::open(path).map_err(|_| Error::PasswordSourceFailed)?)).map(Some),
+        (None, None, None) => Ok(None)
+    }
+}
+
+/// Password of the records file. Sources in order of precedence:
+/// `-p` argument, `--password-fd` descriptor, file named by `TOTPKEEP_PASSWORD_FILE`
+/// and finally the terminal prompt with echo disabled.
+pub fn read_password(arg: Option<&str>, fd: Option<i32>) -> Result<String, Error> {
+    let password = match read_given(arg, fd, PASSWORD_FILE_ENV)? {
+        Some(password) => password,
+        None => prompt("Password: ")?
+    };
+    if password.is_empty() {
+        return Err(Error::EmptyPassword)
+    }
+    Ok(password)
+}
+
+/// New password of the records file, from the same kinds of sources as `read_password`:
+/// `newpass` argument, `--new-password-fd` descriptor, file named by `TOTPKEEP_NEW_PASSWORD_FILE`
+/// and the terminal prompt. Only the password typed on the terminal is asked twice.
+pub fn read_new_password(arg: Option<&str>, fd: Option<i32>) -> Result<String, Error> {
+    match read_given(arg, fd, NEW_PASSWORD_FILE_ENV)? {
+        Some(ref password) if password.is_empty() => Err(Error::EmptyPassword),
+        Some(password) => Ok(password),
+        None => confirm_password(None, "new password")
+    }
+}
+
+/// Password to encrypt something else than the records file, e.g. `"backup password"`.
+/// It has to be typed twice when it isn't given.
+pub fn confirm_password(pass: Option<&str>, what: &str) -> Result<String, Error> {
+    if let Some(pass) = pass {
+        return match pass.is_empty() {
+            true => Err(Error::EmptyPassword),
+            false => Ok(pass.to_string())
+        }
+    }
+    let pass = prompt(&format!("{}{}: ", what[..1].to_uppercase(), &what[1..]))?;
+    if pass.is_empty() {
+        return Err(Error::EmptyPassword)
+    }
+    if prompt(&format!("Repeat {}: ", what))? != pass {
+        return Err(Error::PasswordsDoNotMatch)
+    }
+    Ok(pass)
+}
+
+/// Password of something else than the records file, asked on the terminal unless given.
+pub fn other_password(pass: Option<&str>, what: &str) -> Result<String, Error> {
+    let pass = match pass {
+        Some(p) => p.to_string(),
+        None => prompt(&format!("{}{}: ", what[..1].to_uppercase(), &what[1..]))?
+    };
+    if pass.is_empty() {
+        return Err(Error::EmptyPassword)
+    }
+    Ok(pass)
+}
+
+/// Password of the records file read from its source only when it is needed first,
+/// so that commands served by the unlock agent do not ask for it.
+pub struct Password {
+    arg: Option<String>,
+    fd: Option<i32>,
+    value: RefCell<Option<String>>
+}
+
+impl Password {
+    pub fn new(arg: Option<&str>, fd: Option<i32>) -> Self {
+        Password { arg: arg.map(|p| p.to_string()), fd, value: RefCell::new(None) }
+    }
+
+    pub fn get(&self) -> Result<String, Error> {
+        if let Some(ref value) = *self.value.borrow() {
+            return Ok(value.clone())
+        }
+        let value = read_password(self.arg.as_ref().map(|p| &p[..]), self.fd)?;
+        *self.value.borrow_mut() = Some(value.clone());
+        Ok(value)
+    }
+
+    /// Drops the password read from its source, so that it isn't kept in memory longer than needed.
+    pub fn forget(&self) {
+        *self.value.borrow_mut() = None;
+    }
+}
@@ -0,0 +1,161 @@
+use image;
+use qrcode;
+use rqrr;
+use errors::{Error};
+
+/// Modules around the code left light, as the QR specification asks for.
+const QUIET_ZONE: usize = 4;
+const PNG_SCALE: usize = 8;
+
+/// Texts of all QR codes found in PNG, JPEG or other image file supported by `image` crate.
+/// Decoding is done locally, the image never leaves the machine.
+pub fn decode_image(path: &str) -> Result<Vec<String>, Error> {
+    let grey = image::open(path).map_err(|_| Error::WrongImage)?.to_luma();
+    let (width, height) = grey.dimensions();
+    let pixels = grey.into_raw();
+    let mut prepared = rqrr::PreparedImage::prepare_from_greyscale(width as usize, height as usize,
+        |x, y| pixels[y * width as usize + x]);
+    let texts = prepared.detect_grids().iter()
+        .filter_map(|grid| grid.decode().ok())
+        .map(|(_, text)| text)
+        .collect::<Vec<String>>();
+    if texts.is_empty() {
+        return Err(Error::QrCodeNotFound)
+    }
+    Ok(texts)
+}
+
+/// QR code of the text as rows of modules including the quiet zone, `true` is dark.
+pub fn encode(text: &str) -> Result<Vec<Vec<bool>>, Error> {
+    use qrcode::{Color, QrCode};
+    let code = QrCode::new(text.as_bytes()).map_err(|_| Error::QrEncodingFailed)?;
+    let width = code.width();
+    let colors = code.to_colors();
+    let size = width + 2 * QUIET_ZONE;
+    let mut modules = vec![vec![false; size]; size];
+    for y in 0..width {
+        for x in 0..width {
+            modules[y + QUIET_ZONE][x + QUIET_ZONE] = colors[y * width + x] == Color::Dark;
+        }
+    }
+    Ok(modules)
+}
+
+/// Text rendering for the terminal. Light modules are drawn, so the code reads correctly
+/// on the usual dark background; `invert` is for light backgrounds.
+/// Unicode rendering packs two rows into one line with half blocks, ASCII one uses two characters per module.
+pub fn render_text(modules: &[Vec<bool>], ascii: bool, invert: bool) -> String {
+    let drawn = |dark: bool| dark == invert;
+    let mut text = String::new();
+    if ascii {
+        for row in modules {
+            for &dark in row {
+                text.push_str(if drawn(dark) { "##" } else { "  " });
+            }
+            text.push('\n');
+        }
+        return text
+    }
+    for pair in modules.chunks(2) {
+        for x in 0..pair[0].len() {
+            let top = drawn(pair[0][x]);
+            let bottom = pair.len() > 1 && drawn(pair[1][x]);
+            text.push(match (top, bottom) {
+                (true, true) => '█',
+                (true, false) => '▀',
+                (false, true) => '▄',
+                (false, false) => ' '
+            });
+        }
+        text.push('\n');
+    }
+    text
+}
+
+pub fn to_svg(modules: &[Vec<bool>]) -> String {
+    let size = modules.len();
+    let mut svg = format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+        <svg xmlns=\"http://www.w3.org/2000/svg\" version=\"1.1\" viewBox=\"0 0 {0} {0}\" \
+        width=\"{1}\" height=\"{1}\" shape-rendering=\"crispEdges\">\n\
+        <rect width=\"{0}\" height=\"{0}\" fill=\"#fff\"/>\n<path fill=\"#000\" d=\"", size, size * PNG_SCALE);
+    for (y, row) in modules.iter().enumerate() {
+        for (x, &dark) in row.iter().enumerate() {
+            if dark {
+                svg.push_str(&format!("M{},{}h1v1h-1z", x, y));
+            }
+        }
+    }
+    svg.push_str("\"/>\n</svg>\n");
+    svg
+}
+
+pub fn to_png(modules: &[Vec<bool>]) -> Result<Vec<u8>, Error> {
+    use image::ColorType;
+    use image::png::PNGEncoder;
+    let size = modules.len() * PNG_SCALE;
+    let mut pixels = Vec::with_capacity(size * size);
+    for row in modules {
+        let line = row.iter()
+            .flat_map(|&dark| ::std::iter::repeat(if dark { 0u8 } else { 255u8 }).take(PNG_SCALE))
+            .collect::<Vec<u8>>();
+        for _ in 0..PNG_SCALE {
+            pixels.extend_from_slice(&line);
+        }
+    }
+    let mut png = Vec::new();
+    PNGEncoder::new(&mut png).encode(&pixels, size as u32, size as u32, ColorType::Gray(8))
+        .map_err(|_| Error::QrEncodingFailed)?;
+    Ok(png)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    const URI: &str = "otpauth://totp/ACME%20Co:john@example.com?secret=HXDMVJECJJWSRB3HWIZR4IFUGFTMXBOZ&issuer=ACME%20Co";
+
+    /// Three rows of two modules, the odd count leaves the last half block line with one row.
+    fn modules() -> Vec<Vec<bool>> {
+        vec![vec![true, false], vec![false, false], vec![true, true]]
+    }
+
+    #[test]
+    fn half_blocks() {
+        assert_eq!(render_text(&modules(), false, false), "▄█\n  \n");
+        assert_eq!(render_text(&modules(), false, true), "▀ \n▀▀\n");
+    }
+
+    #[test]
+    fn ascii() {
+        assert_eq!(render_text(&modules(), true, false), "  ##\n####\n    \n");
+        assert_eq!(render_text(&modules(), true, true), "##  \n    \n####\n");
+    }
+
+    #[test]
+    fn text_size() {
+        let modules = vec![vec![false; 25]; 25];
+        let lines = |text: String| text.lines().map(|line| line.chars().count()).collect::<Vec<_>>();
+        assert_eq!(lines(render_text(&modules, false, false)), vec![25; 13]);
+        assert_eq!(lines(render_text(&modules, true, false)), vec![50; 25]);
+    }
+
+    #[test]
+    fn svg_draws_dark_modules() {
+        let svg = to_svg(&[vec![true, false], vec![false, true]]);
+        assert!(svg.contains("viewBox=\"0 0 2 2\" width=\"16\" height=\"16\""));
+        assert!(svg.contains("d=\"M0,0h1v1h-1zM1,1h1v1h-1z\""));
+    }
+
+    #[test]
+    fn png_round_trip() {
+        let png = to_png(&encode(URI).unwrap()).unwrap();
+        let path = env::temp_dir().join(format!("totpkeep-qr-{}.png", process::id()));
+        fs::write(&path, &png).unwrap();
+        let decoded = decode_image(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        assert_eq!(decoded.unwrap(), [URI]);
+    }
+}
@@ -0,0 +1,198 @@
+use errors::{Error};
+
+/// What a selector is matched against for a single record.
+pub struct Candidate<'a> {
+    pub id: &'a str,
+    pub name: &'a str,
+    /// name with issuer, as shown by `list`
+    pub display_name: String,
+    pub tags: &'a [String]
+}
+
+/// Shortest accepted `id:` prefix, so that a typo does not select a random record.
+const MIN_ID_PREFIX: usize = 4;
+
+fn single(matches: Vec<usize>, candidates: &[Candidate]) -> Result<Option<usize>, Error> {
+    match matches.len() {
+        0 => Ok(None),
+        1 => Ok(Some(matches[0])),
+        _ => Err(Error::AmbiguousRecord(matches.iter()
+            .map(|&i| format!("{}. {} (id:{})", i + 1, candidates[i].display_name, candidates[i].id))
+            .collect()))
+    }
+}
+
+/// Characters of `needle` appear in `haystack` in the same order, e.g. "gthb" in "GitHub".
+pub fn fuzzy_match(needle: &str, haystack: &str) -> bool {
+    let mut haystack = haystack.chars();
+    needle.chars().all(|c| haystack.any(|h| h == c))
+}
+
+/// Rule of `resolve` the record was selected by.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Match {
+    Id,
+    Tag,
+    Index,
+    /// exact name with or without issuer
+    Name,
+    /// name ignoring case, substring or fuzzy match
+    Approximate
+}
+
+impl Match {
+    /// Whether the selector identifies the record rather than guesses it,
+    /// so that destructive commands may act without asking.
+    pub fn is_exact(&self) -> bool {
+        match *self {
+            Match::Id | Match::Index | Match::Name => true,
+            Match::Tag | Match::Approximate => false
+        }
+    }
+}
+
+/// Resolves the selector to position of the record in `candidates`. Accepted in order of precedence:
+/// `id:` followed by the ID or its unique prefix, `tag:` followed by a tag of the single record, 1-based index as shown by `list`, exact name with or
+/// without issuer, the same ignoring case, unique case-insensitive substring and unique fuzzy match.
+/// The first rule with any match decides; more than one match is an error listing them.
+pub fn resolve(selector: &str, candidates: &[Candidate]) -> Result<usize, Error> {
+    resolve_match(selector, candidates).map(|(index, _)| index)
+}
+
+/// As `resolve`, along with the rule that decided.
+pub fn resolve_match(selector: &str, candidates: &[Candidate]) -> Result<(usize, Match), Error> {
+    let selector = selector.trim();
+    if selector.is_empty() {
+        return Err(Error::RecordNotFound)
+    }
+    if selector.starts_with("id:") {
+        let prefix = selector[3..].trim().to_lowercase();
+        if prefix.len() < MIN_ID_PREFIX {
+            return Err(Error::RecordNotFound)
+        }
+        let matches = (0..candidates.len()).filter(|&i| candidates[i].id.starts_with(&prefix[..])).collect();
+        return single(matches, candidates)?.map(|i| (i, Match::Id)).ok_or(Error::RecordNotFound)
+    }
+    if selector.starts_with("tag:") {
+        let tag = selector[4..].trim().to_lowercase();
+        let matches = (0..candidates.len())
+            .filter(|&i| candidates[i].tags.iter().any(|t| t.to_lowercase() == tag))
+            .collect();
+        return single(matches, candidates)?.map(|i| (i, Match::Tag)).ok_or(Error::RecordNotFound)
+    }
+    if selector.chars().all(|c| c.is_ascii_digit()) {
+        return match selector.parse::<usize>() {
+            Ok(index) if index >= 1 && index <= candidates.len() => Ok((index - 1, Match::Index)),
+            _ => Err(Error::WrongRecordIndex)
+        }
+    }
+
+    let lower = selector.to_lowercase();
+    let rules: [(Match, &Fn(&Candidate) -> bool); 4] = [
+        (Match::Name, &|c| c.name == selector || c.display_name == selector),
+        (Match::Approximate, &|c| c.name.to_lowercase() == lower || c.display_name.to_lowercase() == lower),
+        (Match::Approximate, &|c| c.display_name.to_lowercase().contains(&lower[..])),
+        (Match::Approximate, &|c| fuzzy_match(&lower, &c.display_name.to_lowercase()))
+    ];
+    for &(kind, rule) in rules.iter() {
+        let matches = (0..candidates.len()).filter(|&i| rule(&candidates[i])).collect();
+        if let Some(found) = single(matches, candidates)? {
+            return Ok((found, kind))
+        }
+    }
+    Err(Error::RecordNotFound)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NO_TAGS: &[String] = &[];
+
+    fn candidate<'a>(id: &'a str, name: &'a str, issuer: Option<&str>, tags: &'a [String]) -> Candidate<'a> {
+        let display_name = match issuer {
+            Some(issuer) => format!("{}:{}", issuer, name),
+            None => name.to_string()
+        };
+        Candidate { id, name, display_name, tags }
+    }
+
+    fn resolved(selector: &str, candidates: &[Candidate]) -> Result<usize, String> {
+        resolve(selector, candidates).map_err(|e| match e {
+            Error::AmbiguousRecord(matches) => matches.join("; "),
+            other => other.to_string()
+        })
+    }
+
+    #[test]
+    fn precedence() {
+        let work = vec![String::from("work")];
+        let candidates = vec![
+            candidate("a1b2c3d4e5f60718", "github", Some("GitHub"), &work),
+            candidate("a1b2ffff00000000", "git", None, NO_TAGS),
+            candidate("0000000000000002", "2", Some("Two"), NO_TAGS),
+            candidate("9999999999999999", "GitLab", None, NO_TAGS)
+        ];
+        assert_eq!(resolved("id:a1b2c", &candidates), Ok(0));
+        assert_eq!(resolved("id: A1B2F", &candidates), Ok(1));
+        assert_eq!(resolved("tag:Work", &candidates), Ok(0));
+        // index goes before the name "2"
+        assert_eq!(resolved("2", &candidates), Ok(1));
+        // exact name goes before the substring of "github" and "GitLab"
+        assert_eq!(resolved("git", &candidates), Ok(1));
+        assert_eq!(resolved("GitHub:github", &candidates), Ok(0));
+        // exact match ignoring case goes before the substring
+        assert_eq!(resolved("GITLAB", &candidates), Ok(3));
+        assert_eq!(resolved("hub", &candidates), Ok(0));
+        assert_eq!(resolved("gtlb", &candidates), Ok(3));
+        assert_eq!(resolved("  Two:2 ", &candidates), Ok(2));
+    }
+
+    #[test]
+    fn ambiguous() {
+        let candidates = vec![
+            candidate("a1b2c3d4e5f60718", "alice", Some("GitHub"), NO_TAGS),
+            candidate("a1b2ffff00000000", "alice", Some("GitLab"), NO_TAGS)
+        ];
+        assert_eq!(resolved("alice", &candidates),
+                   Err(String::from("1. GitHub:alice (id:a1b2c3d4e5f60718); 2. GitLab:alice (id:a1b2ffff00000000)")));
+        assert!(resolved("id:a1b2", &candidates).is_err());
+        assert!(resolved("git", &candidates).is_err());
+        assert_eq!(resolved("lab", &candidates), Ok(1));
+    }
+
+    #[test]
+    fn not_found() {
+        let candidates = vec![candidate("a1b2c3d4e5f60718", "alice", None, NO_TAGS)];
+        assert_eq!(resolved("", &candidates), Err(Error::RecordNotFound.to_string()));
+        assert_eq!(resolved("bob", &candidates), Err(Error::RecordNotFound.to_string()));
+        assert_eq!(resolved("tag:none", &candidates), Err(Error::RecordNotFound.to_string()));
+        // too short to be a deliberate ID
+        assert_eq!(resolved("id:a1b", &candidates), Err(Error::RecordNotFound.to_string()));
+        assert_eq!(resolved("0", &candidates), Err(Error::WrongRecordIndex.to_string()));
+        assert_eq!(resolved("2", &candidates), Err(Error::WrongRecordIndex.to_string()));
+    }
+
+    #[test]
+    fn exactness() {
+        let work = vec![String::from("work")];
+        let candidates = vec![candidate("a1b2c3d4e5f60718", "github", Some("GitHub"), &work)];
+        let kind = |selector| resolve_match(selector, &candidates).unwrap().1;
+        assert_eq!(kind("id:a1b2"), Match::Id);
+        assert_eq!(kind("1"), Match::Index);
+        assert_eq!(kind("github"), Match::Name);
+        assert_eq!(kind("GitHub:github"), Match::Name);
+        assert_eq!(kind("tag:work"), Match::Tag);
+        assert_eq!(kind("GITHUB"), Match::Approximate);
+        assert_eq!(kind("hub"), Match::Approximate);
+        assert!(kind("1").is_exact() && !kind("gthb").is_exact() && !kind("tag:work").is_exact());
+    }
+
+    #[test]
+    fn fuzzy() {
+        assert!(fuzzy_match("gthb", "github"));
+        assert!(fuzzy_match("", "github"));
+        assert!(!fuzzy_match("hg", "github"));
+        assert!(!fuzzy_match("githubs", "github"));
+    }
+}
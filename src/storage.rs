@@ -0,0 +1,325 @@
+use std::env;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use errors::{Error};
+
+/// Environment variable overriding the number of kept backup generations. 0 disables backups.
+pub const BACKUPS_ENV: &str = "TOTPKEEP_BACKUPS";
+const DEFAULT_BACKUPS: usize = 5;
+/// Environment variable overriding how many seconds to wait for the lock held by another process.
+pub const LOCK_TIMEOUT_ENV: &str = "TOTPKEEP_LOCK_TIMEOUT";
+const DEFAULT_LOCK_TIMEOUT: u64 = 10;
+const LOCK_RETRY_MILLIS: u64 = 100;
+
+pub struct Backup {
+    pub generation: usize,
+    pub path: PathBuf,
+    pub len: u64,
+    pub modified: Option<SystemTime>
+}
+
+fn backups_count() -> usize {
+    env::var(BACKUPS_ENV).ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_BACKUPS)
+}
+
+fn lock_timeout() -> Duration {
+    let secs = env::var(LOCK_TIMEOUT_ENV).ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_LOCK_TIMEOUT);
+    Duration::from_secs(secs)
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name: OsString = path.as_os_str().to_os_string();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// `file.tkp.1` is the most recent previous content, `file.tkp.2` the one before it and so on.
+pub fn backup_path(path: &Path, generation: usize) -> PathBuf {
+    with_suffix(path, &format!(".{}", generation))
+}
+
+pub fn read(path: &Path) -> Result<Vec<u8>, Error> {
+    let mut content: Vec<u8> = Vec::new();
+    File::open(path)?.read_to_end(&mut content)?;
+    Ok(content)
+}
+
+#[cfg(unix)]
+fn create_private(path: &Path) -> Result<File, Error> {
+    use std::os::unix::fs::OpenOptionsExt;
+    Ok(OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)?)
+}
+
+#[cfg(not(unix))]
+fn create_private(path: &Path) -> Result<File, Error> {
+    Ok(OpenOptions::new().write(true).create_new(true).open(path)?)
+}
+
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> Result<(), Error> {
+    let dir = match path.parent() {
+        Some(dir) if dir.as_os_str().is_empty() => Path::new("."),
+        Some(dir) => dir,
+        None => return Ok(())
+    };
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Keeps the current content under a temporary name, as it becomes a backup only once
+/// the new content is in place. `None` when there is nothing to keep.
+fn keep_current(path: &Path, generations: usize) -> Result<Option<PathBuf>, Error> {
+    if generations == 0 || !path.exists() {
+        return Ok(None)
+    }
+    let kept = with_suffix(path, &format!(".1.tmp.{}", process::id()));
+    if fs::hard_link(path, &kept).is_err() {
+        if let Err(err) = fs::copy(path, &kept) {
+            let _ = fs::remove_file(&kept);
+            return Err(Error::from(err))
+        }
+    }
+    Ok(Some(kept))
+}
+
+/// Shifts `file.1` .. `file.(n-1)` one generation up and makes the kept previous content `file.1`.
+fn rotate_backups(path: &Path, kept: &Path, generations: usize) -> Result<(), Error> {
+    for generation in (1..generations).rev() {
+        let from = backup_path(path, generation);
+        if from.exists() {
+            fs::rename(&from, backup_path(path, generation + 1))?;
+        }
+    }
+    fs::rename(kept, backup_path(path, 1))?;
+    sync_dir(path)
+}
+
+/// Replaces the file so that at any moment either the old or the new content is complete on disk:
+/// the content goes to a temporary file in the same directory, is flushed to disk and renamed over
+/// the original. Previous content is kept as a backup generation.
+pub fn write_atomic(path: &Path, content: &[u8]) -> Result<(), Error> {
+    replace(path, content, backups_count())
+}
+
+/// Like `write_atomic`, but without a new backup generation. For bookkeeping changes,
+/// such as last use time, that should not push real backups out.
+pub fn write_atomic_no_backup(path: &Path, content: &[u8]) -> Result<(), Error> {
+    replace(path, content, 0)
+}
+
+fn replace(path: &Path, content: &[u8], backups: usize) -> Result<(), Error> {
+    let tmp = with_suffix(path, &format!(".tmp.{}", process::id()));
+    let written = create_private(&tmp).and_then(|mut file| {
+        file.write_all(content)?;
+        file.sync_all()?;
+        Ok(())
+    });
+    let kept = match written.and_then(|_| keep_current(path, backups)) {
+        Ok(kept) => kept,
+        Err(err) => {
+            let _ = fs::remove_file(&tmp);
+            return Err(err)
+        }
+    };
+    // backups are untouched until here, a failed write leaves them as they were
+    if let Err(err) = fs::rename(&tmp, path) {
+        let _ = fs::remove_file(&tmp);
+        if let Some(ref kept) = kept {
+            let _ = fs::remove_file(kept);
+        }
+        return Err(Error::from(err))
+    }
+    sync_dir(path)?;
+    if let Some(kept) = kept {
+        // the new content is saved, failing the command now would only invite repeating it
+        if let Err(err) = rotate_backups(path, &kept, backups) {
+            let _ = fs::remove_file(&kept);
+            eprintln!("Warning: {}, backups were not rotated", err);
+        }
+    }
+    Ok(())
+}
+
+/// Writes a new file readable only by the owner, for exports holding plain secrets.
+/// Never overwrites existing file.
+pub fn write_new(path: &Path, content: &[u8]) -> Result<(), Error> {
+    let mut file = create_private(path).map_err(|err| match fs::symlink_metadata(path) {
+        Ok(_) => Error::FileExists,
+        Err(_) => err
+    })?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Existing backups, most recent first.
+pub fn list_backups(path: &Path) -> Vec<Backup> {
+    let mut backups = Vec::new();
+    let mut generation = 1;
+    loop {
+        let backup = backup_path(path, generation);
+        match fs::metadata(&backup) {
+            Ok(meta) => backups.push(Backup {
+                generation,
+                path: backup,
+                len: meta.len(),
+                modified: meta.modified().ok()
+            }),
+            Err(_) => break
+        }
+        generation += 1;
+    }
+    backups
+}
+
+/// Exclusive advisory lock held until dropped, as closing the file releases it.
+/// Taken on the sidecar `file.lock`, since the file itself is replaced by rename on every write.
+pub struct FileLock {
+    _file: File
+}
+
+#[cfg(unix)]
+fn try_lock(file: &File) -> Result<bool, Error> {
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+    use libc;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(true)
+    }
+    match io::Error::last_os_error().raw_os_error() {
+        Some(code) if code == libc::EWOULDBLOCK => Ok(false),
+        _ => Err(Error::FileError)
+    }
+}
+
+#[cfg(not(unix))]
+fn try_lock(_file: &File) -> Result<bool, Error> {
+    Ok(true)
+}
+
+/// Waits up to `TOTPKEEP_LOCK_TIMEOUT` seconds for other processes modifying the file.
+pub fn lock(path: &Path) -> Result<FileLock, Error> {
+    lock_within(path, lock_timeout())
+}
+
+/// Like `lock`, but gives up at once when another process holds the lock.
+pub fn lock_nowait(path: &Path) -> Result<FileLock, Error> {
+    lock_within(path, Duration::from_secs(0))
+}
+
+fn lock_within(path: &Path, timeout: Duration) -> Result<FileLock, Error> {
+    let lock_path = with_suffix(path, ".lock");
+    let file = OpenOptions::new().read(true).write(true).create(true).open(&lock_path)?;
+    let started = Instant::now();
+    while !try_lock(&file)? {
+        if started.elapsed() >= timeout {
+            return Err(Error::FileLocked)
+        }
+        thread::sleep(Duration::from_millis(LOCK_RETRY_MILLIS));
+    }
+    Ok(FileLock { _file: file })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Empty directory of its own for every test, removed when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let dir = env::temp_dir().join(format!("totpkeep-storage-{}-{}", process::id(), name));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir(&dir).unwrap();
+            TempDir(dir)
+        }
+
+        fn file(&self) -> PathBuf {
+            self.0.join("file.tkp")
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn contents(path: &Path) -> Vec<String> {
+        let mut all = vec![String::from_utf8(read(path).unwrap()).unwrap()];
+        all.extend(list_backups(path).iter().map(|b| String::from_utf8(read(&b.path).unwrap()).unwrap()));
+        all
+    }
+
+    fn names(dir: &Path) -> Vec<String> {
+        let mut names = fs::read_dir(dir).unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn oldest_backup_dropped() {
+        let dir = TempDir::new("rotate");
+        let path = dir.file();
+        for content in &["a", "b", "c", "d", "e"] {
+            replace(&path, content.as_bytes(), 3).unwrap();
+        }
+        assert_eq!(contents(&path), ["e", "d", "c", "b"]);
+        assert_eq!(names(&dir.0), ["file.tkp", "file.tkp.1", "file.tkp.2", "file.tkp.3"]);
+    }
+
+    #[test]
+    fn backups_disabled() {
+        let dir = TempDir::new("disabled");
+        let path = dir.file();
+        // the only test reading the variable, others pass the count directly
+        env::set_var(BACKUPS_ENV, "0");
+        write_atomic(&path, b"a").unwrap();
+        write_atomic(&path, b"b").unwrap();
+        env::remove_var(BACKUPS_ENV);
+        assert_eq!(contents(&path), ["b"]);
+        assert_eq!(names(&dir.0), ["file.tkp"]);
+    }
+
+    #[test]
+    fn no_backup_write_keeps_generations() {
+        let dir = TempDir::new("no-backup");
+        let path = dir.file();
+        replace(&path, b"a", 5).unwrap();
+        replace(&path, b"b", 5).unwrap();
+        write_atomic_no_backup(&path, b"b used").unwrap();
+        assert_eq!(contents(&path), ["b used", "a"]);
+    }
+
+    #[test]
+    fn failed_write_changes_nothing() {
+        let dir = TempDir::new("failed");
+        let path = dir.file();
+        replace(&path, b"a", 5).unwrap();
+        replace(&path, b"b", 5).unwrap();
+        // the temporary file can't be created where a directory is in the way
+        let tmp = with_suffix(&path, &format!(".tmp.{}", process::id()));
+        fs::create_dir(&tmp).unwrap();
+        assert!(replace(&path, b"c", 5).is_err());
+        fs::remove_dir(&tmp).unwrap();
+        assert_eq!(contents(&path), ["b", "a"]);
+        assert_eq!(names(&dir.0), ["file.tkp", "file.tkp.1"]);
+    }
+}
@@ -1,5 +1,11 @@
 use std::collections::HashMap;
 use std::iter::repeat;
+use std::ops::Range;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+/// Narrowest a shrinking column gets before the table is stacked instead.
+const MIN_SHRUNK_WIDTH: usize = 6;
 
 pub trait TableSymbols {
     fn print_top(&self) -> bool;
@@ -29,6 +35,8 @@ pub trait TableSymbols {
     fn progress_left(&self) -> &'static str;
     fn progress_right(&self) -> &'static str;
     fn progress_middle(&self) -> &'static str;
+    // end of shortened text
+    fn ellipsis(&self) -> &'static str;
 }
 
 pub struct UnicodeTableSymbols;
@@ -61,7 +69,7 @@ impl TableSymbols for UnicodeTableSymbols {
     fn progress_left(&self) -> &'static str {"│"}
     fn progress_middle(&self) -> &'static str {"░"}
     fn progress_right(&self) -> &'static str {"│"}
-
+    fn ellipsis(&self) -> &'static str {"…"}
 }
 
 pub struct AsciiTableSymbols;
@@ -94,9 +102,47 @@ impl TableSymbols for AsciiTableSymbols {
     fn progress_left(&self) -> &'static str {"["}
     fn progress_middle(&self) -> &'static str {"="}
     fn progress_right(&self) -> &'static str {"]"}
+    fn ellipsis(&self) -> &'static str {"..."}
+}
+
+/// Columns the text takes on the terminal. Grapheme clusters are measured as a whole: combining
+/// marks take no column, East Asian wide characters and emoji, also those joined of several, take two.
+pub fn display_width(text: &str) -> usize {
+    text.graphemes(true).map(grapheme_width).sum()
+}
 
+fn grapheme_width(grapheme: &str) -> usize {
+    // emoji presentation selector makes narrow symbols such as ❤ wide
+    match grapheme.contains('\u{fe0f}') {
+        true => 2,
+        false => UnicodeWidthStr::width(grapheme).min(2)
+    }
 }
 
+/// Text cut to at most `width` columns, ending with the ellipsis when anything was cut.
+pub fn truncate(text: &str, width: usize, ellipsis: &str) -> String {
+    if display_width(text) <= width {
+        return text.to_string()
+    }
+    let available = width.saturating_sub(display_width(ellipsis));
+    let mut result = String::new();
+    let mut used = 0;
+    for grapheme in text.graphemes(true) {
+        used += grapheme_width(grapheme);
+        if used > available {
+            break
+        }
+        result.push_str(grapheme);
+    }
+    result.push_str(ellipsis);
+    result
+}
+
+fn spaces(count: usize) -> String {
+    repeat(" ").take(count).collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Alignment {
     Left,
     Right,
@@ -106,40 +152,121 @@ pub enum Alignment {
 pub struct RowFormat {
     pub padding: u8,
     pub alignment: Alignment,
-    pub row_numbers: bool
+    pub row_numbers: bool,
+    /// column may be ellipsized when the table is wider than `max_width`
+    pub shrink: bool
 }
 
 impl RowFormat {
     pub fn default() -> Self {
-        RowFormat{padding: 1, alignment: Alignment::Middle, row_numbers: false}
+        RowFormat{padding: 1, alignment: Alignment::Middle, row_numbers: false, shrink: false}
     }
 }
 
+/// Data widths of the columns, or one `header: value` line per cell when the columns don't fit.
+enum Layout {
+    Columns(Vec<usize>),
+    Stacked
+}
+
 pub struct StringTableFormatter<'a> {
     table_symbols: &'a TableSymbols,
-    pub rows: Vec<RowFormat>
+    pub rows: Vec<RowFormat>,
+    /// Columns the table may take, usually the width of the terminal. Unlimited when `None`.
+    pub max_width: Option<usize>
 }
 
 impl<'a> StringTableFormatter<'a>{
     pub fn new(symbols: &'a TableSymbols) -> Self {
-        StringTableFormatter {table_symbols: symbols, rows: Vec::new()}
+        StringTableFormatter {table_symbols: symbols, rows: Vec::new(), max_width: None}
     }
 
     pub fn format(&self, table: &Table) -> String {
+        let widths = match self.layout(table) {
+            Layout::Columns(widths) => widths,
+            Layout::Stacked => return self.format_stacked(table)
+        };
         let mut result = String::new();
-        result.push_str(&self.format_header(table));
-        result.push_str(&self.format_body(table));
-        result.push_str(&self.format_footer(table));
+        result.push_str(&self.format_header(table, &widths));
+        result.push_str(&self.format_body(table, &widths));
+        result.push_str(&self.format_footer(&widths));
         result
     }
 
-    fn format_header(&self, table: &Table) -> String {
+    /// Lines of the formatted table the data row is on, counting from 0.
+    pub fn data_row_lines(&self, table: &Table, row: usize) -> Range<usize> {
+        match self.layout(table) {
+            Layout::Columns(_) => {
+                let header = 1 + self.table_symbols.print_top() as usize + self.table_symbols.print_head_bottom() as usize;
+                let start = header + row * (1 + self.table_symbols.print_mid() as usize);
+                start..start + 1
+            },
+            Layout::Stacked => {
+                let fields = table.fields.len();
+                row * (fields + 1)..row * (fields + 1) + fields
+            }
+        }
+    }
+
+    /// Shrinks the columns that may be shrunk, the last one first, until the table fits in `max_width`.
+    fn layout(&self, table: &Table) -> Layout {
+        let mut widths = (0..table.fields.len())
+            .map(|index| self.get_field_data_max_len(index, table))
+            .collect::<Vec<_>>();
+        let max_width = match self.max_width {
+            Some(width) => width,
+            None => return Layout::Columns(widths)
+        };
+        let symbols = self.table_symbols;
+        let frame = display_width(symbols.left()) + display_width(symbols.right())
+            + display_width(symbols.middle()) * widths.len().saturating_sub(1)
+            + (0..widths.len()).map(|index| self.rows[index].padding as usize * 2).sum::<usize>();
+        let mut excess = (frame + widths.iter().sum::<usize>()).saturating_sub(max_width);
+        for index in (0..widths.len()).rev() {
+            if excess == 0 {
+                break
+            }
+            if self.rows[index].shrink && widths[index] > MIN_SHRUNK_WIDTH {
+                let cut = excess.min(widths[index] - MIN_SHRUNK_WIDTH);
+                widths[index] -= cut;
+                excess -= cut;
+            }
+        }
+        match excess {
+            0 => Layout::Columns(widths),
+            _ => Layout::Stacked
+        }
+    }
+
+    /// Rows one after another, every cell on its own line after the header, rows separated by an empty line.
+    fn format_stacked(&self, table: &Table) -> String {
+        let headers = table.fields.iter()
+            .map(|field| table.headers.get(field).map(|s| &s[..]).unwrap_or(""))
+            .collect::<Vec<_>>();
+        let label_width = headers.iter().map(|header| display_width(header) + 1).max().unwrap_or(0);
+        let max_width = self.max_width.unwrap_or(usize::max_value());
+        let mut result = String::new();
+        for row in 0..table.rows.len() {
+            if row > 0 {
+                result.push_str("\n");
+            }
+            for column in 0..table.fields.len() {
+                let data = table.get_cell_data(row, column).map(|s| &s[..]).unwrap_or("");
+                let line = format!("{} {}", pad(&format!("{}:", headers[column]), label_width, &Alignment::Left), data);
+                result.push_str(&truncate(line.trim_right(), max_width, self.table_symbols.ellipsis()));
+                result.push_str("\n");
+            }
+        }
+        result
+    }
+
+    fn format_header(&self, table: &Table, widths: &[usize]) -> String {
         let mut result = String::new();
         // Top header line
         if self.table_symbols.print_top() {
             result.push_str(
                 &(self.format_div_row(
-                    table,
+                    widths,
                     self.table_symbols.top_left(),
                     self.table_symbols.top_mid(),
                     self.table_symbols.top_right(),
@@ -156,7 +283,7 @@ impl<'a> StringTableFormatter<'a>{
                 Some(s) => &s[..],
                 None => ""
             };
-            result.push_str(&self.format_data_cell(column, table, header));
+            result.push_str(&self.format_data_cell(column, widths, header));
             if max_column - column > 1 {
                 result.push_str(self.table_symbols.middle());
             }
@@ -168,7 +295,7 @@ impl<'a> StringTableFormatter<'a>{
         if self.table_symbols.print_head_bottom() {
             result.push_str(
                 &self.format_div_row(
-                    table,
+                    widths,
                     self.table_symbols.mid_left(),
                     self.table_symbols.mid_mid(),
                     self.table_symbols.mid_right(),
@@ -179,12 +306,13 @@ impl<'a> StringTableFormatter<'a>{
         result
     }
 
-    fn format_body(&self, table: &Table) -> String {
+    fn format_body(&self, table: &Table, widths: &[usize]) -> String {
         let mut result = String::new();
         let rows_num = table.rows.len();
         for row in 0..rows_num {
             result.push_str(&self.format_data_row(
                 table,
+                widths,
                 self.table_symbols.left(),
                 self.table_symbols.middle(),
                 self.table_symbols.right(),
@@ -193,7 +321,7 @@ impl<'a> StringTableFormatter<'a>{
             result.push_str("\n");
             if rows_num - row > 1 && self.table_symbols.print_mid() {
                 result.push_str(&self.format_div_row(
-                    table,
+                    widths,
                     self.table_symbols.mid_left(),
                     self.table_symbols.mid_mid(),
                     self.table_symbols.mid_right(),
@@ -205,12 +333,12 @@ impl<'a> StringTableFormatter<'a>{
         result
     }
 
-    fn format_footer(&self, table: &Table) -> String {
+    fn format_footer(&self, widths: &[usize]) -> String {
         let mut result = String::new();
         if self.table_symbols.print_bottom() {
             result.push_str(
                 &self.format_div_row(
-                    table,
+                    widths,
                     self.table_symbols.bottom_left(),
                     self.table_symbols.bottom_mid(),
                     self.table_symbols.bottom_right(),
@@ -221,12 +349,12 @@ impl<'a> StringTableFormatter<'a>{
         result
     }
 
-    fn format_div_row(&self, table: &Table, left: &'static str, middle: &'static str,
+    fn format_div_row(&self, widths: &[usize], left: &'static str, middle: &'static str,
                       right: &'static str, fill_char: &'static str) -> String {
         let mut line = String::from(left);
-        let max_index = table.fields.len();
+        let max_index = widths.len();
         for index in 0..max_index {
-            line.push_str(&self.format_div_cell(index, table, fill_char));
+            line.push_str(&self.format_div_cell(index, widths, fill_char));
             if max_index - index > 1 {
                 line.push_str(middle);
             }
@@ -235,14 +363,14 @@ impl<'a> StringTableFormatter<'a>{
         line
     }
 
-    fn format_div_cell(&self, index: usize, table: &Table, symbol: &'static str) -> String {
-        let length = self.get_field_formatted_len(index, table);
+    fn format_div_cell(&self, index: usize, widths: &[usize], symbol: &'static str) -> String {
+        let length = self.rows[index].padding as usize * 2 + widths[index];
         let mut line = String::with_capacity(length);
         repeat(symbol).take(length).for_each(|ch| line.push_str(ch));
         line
     }
 
-    fn format_data_row(&self, table: &Table, left: &'static str, middle: &'static str,
+    fn format_data_row(&self, table: &Table, widths: &[usize], left: &'static str, middle: &'static str,
                        right: &'static str, row: usize) -> String {
         let mut line = String::from(left);
         let max_column = table.fields.len();
@@ -251,7 +379,7 @@ impl<'a> StringTableFormatter<'a>{
                 Some(s) => &s[..],
                 None => ""
             };
-            line.push_str(&self.format_data_cell(column, table, cell_data));
+            line.push_str(&self.format_data_cell(column, widths, cell_data));
             if max_column - column > 1 {
                 line.push_str(middle);
             }
@@ -260,15 +388,10 @@ impl<'a> StringTableFormatter<'a>{
         line
     }
 
-    fn format_data_cell(&self, index: usize, table: &Table, data: &str) -> String {
-        let padding = self.rows[index].padding as usize;
-        let field_len = self.get_field_data_max_len(index, table);
-        let p = format!("{p:^padding$}", p="", padding=padding);
-        match self.rows[index].alignment {
-            ref Left => format!("{p}{d:<w$}{p}", p=p, w=field_len, d=data),
-            ref Middle => format!("{p}{d:^w$}{p}", p=p, w=field_len, d=data),
-            ref Right => format!("{p}{d:>w$}{p}", p=p, w=field_len, d=data)
-        }
+    fn format_data_cell(&self, index: usize, widths: &[usize], data: &str) -> String {
+        let p = spaces(self.rows[index].padding as usize);
+        let data = truncate(data, widths[index], self.table_symbols.ellipsis());
+        format!("{p}{d}{p}", p=p, d=pad(&data, widths[index], &self.rows[index].alignment))
     }
 
     fn get_field_data_max_len(&self, index: usize, table: &Table) -> usize {
@@ -278,12 +401,17 @@ impl<'a> StringTableFormatter<'a>{
             None => 0usize
         }
     }
+}
 
-    fn get_field_formatted_len(&self, index: usize, table: &Table) -> usize {
-        let padding = self.rows[index].padding as usize;
-        let field_len = self.get_field_data_max_len(index, table);
-        padding * 2 + field_len
-    }
+/// Text filled with spaces to `width` columns, wider text is left as it is.
+fn pad(text: &str, width: usize, alignment: &Alignment) -> String {
+    let fill = width.saturating_sub(display_width(text));
+    let (left, right) = match *alignment {
+        Alignment::Left => (0, fill),
+        Alignment::Right => (fill, 0),
+        Alignment::Middle => (fill / 2, fill - fill / 2)
+    };
+    format!("{}{}{}", spaces(left), text, spaces(right))
 }
 
 type Row = HashMap<String, String>;
@@ -312,7 +440,7 @@ impl  Table {
 
     pub fn append_header(&mut self, name: &str, value: &str) {
         self.headers.insert(name.to_string(), value.to_string());
-        self.update_max_len(name, value.len() as usize);
+        self.update_max_len(name, display_width(value));
     }
 
     pub fn get_cell_data(&self, row: usize, column: usize) -> Option<&String> {
@@ -326,7 +454,7 @@ impl  Table {
             let k = names[index as usize];
             let v = values[index as usize];
             row.insert(k.to_string(), v.to_string());
-            self.update_max_len(k, v.len() as usize);
+            self.update_max_len(k, display_width(v));
         });
         self.rows.push(row);
     }
@@ -341,3 +469,89 @@ impl  Table {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn widths() {
+        assert_eq!(display_width("abc"), 3);
+        assert_eq!(display_width("Привет"), 6);
+        // wide CJK
+        assert_eq!(display_width("東京銀行"), 8);
+        // e and combining acute accent
+        assert_eq!(display_width("cafe\u{301}"), 4);
+        // family joined with zero width joiners is one emoji
+        assert_eq!(display_width("\u{1f468}\u{200d}\u{1f469}\u{200d}\u{1f467}"), 2);
+        // heart with the emoji presentation selector
+        assert_eq!(display_width("\u{2764}\u{fe0f}"), 2);
+        assert_eq!(display_width(""), 0);
+    }
+
+    #[test]
+    fn truncation() {
+        assert_eq!(truncate("short", 5, "…"), "short");
+        assert_eq!(truncate("longer", 5, "…"), "long…");
+        assert_eq!(truncate("longer", 5, "..."), "lo...");
+        // wide character doesn't fit in the single column left
+        assert_eq!(truncate("東京銀行", 6, "…"), "東京…");
+        assert_eq!(truncate("東京銀行", 7, "…"), "東京銀…");
+        // combining mark stays with its letter
+        assert_eq!(truncate("cafe\u{301}ss", 5, "…"), "cafe\u{301}…");
+        // joined emoji is never split
+        let family = "\u{1f468}\u{200d}\u{1f469}\u{200d}\u{1f467}";
+        assert_eq!(truncate(&format!("a{}b", family), 3, "…"), "a…");
+        assert_eq!(truncate(&format!("a{}bc", family), 4, "…"), format!("a{}…", family));
+        for width in 0..10 {
+            assert!(display_width(&truncate("東京銀行 café", width, "…")) <= width.max(1));
+        }
+    }
+
+    fn table(names: &[&str]) -> Table {
+        let mut table = Table::new();
+        table.add_field("name");
+        table.add_field("code");
+        table.append_header("name", "Name");
+        table.append_header("code", "Code");
+        for name in names {
+            table.add_row(&["name", "code"], &[name, "123456"]);
+        }
+        table
+    }
+
+    fn formatter(symbols: &TableSymbols, max_width: Option<usize>) -> StringTableFormatter {
+        let mut formatter = StringTableFormatter::new(symbols);
+        formatter.rows = vec![RowFormat { shrink: true, ..RowFormat::default() }, RowFormat::default()];
+        formatter.max_width = max_width;
+        formatter
+    }
+
+    #[test]
+    fn columns_are_aligned_by_display_width() {
+        let table = table(&["東京", "ab"]);
+        let formatted = formatter(&AsciiTableSymbols, None).format(&table);
+        let widths = formatted.lines().map(display_width).collect::<Vec<_>>();
+        assert!(widths.iter().all(|&w| w == widths[0]), "{}", formatted);
+    }
+
+    #[test]
+    fn shrinks_to_fit() {
+        let table = table(&["a rather long account name"]);
+        let formatter = formatter(&AsciiTableSymbols, Some(30));
+        let formatted = formatter.format(&table);
+        assert!(formatted.lines().all(|line| display_width(line) <= 30), "{}", formatted);
+        assert!(formatted.contains("..."));
+        let row = formatter.data_row_lines(&table, 0);
+        assert!(formatted.lines().nth(row.start).unwrap().contains("123456"));
+    }
+
+    #[test]
+    fn stacks_when_columns_dont_fit() {
+        let table = table(&["first account", "second"]);
+        let formatter = formatter(&AsciiTableSymbols, Some(12));
+        let formatted = formatter.format(&table);
+        assert_eq!(formatted, "Name: fir...\nCode: 123456\n\nName: second\nCode: 123456\n");
+        assert_eq!(formatter.data_row_lines(&table, 1), 3..5);
+    }
+}
@@ -0,0 +1,291 @@
+use std::fs::OpenOptions;
+use std::io::{stdout, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use base64;
+use errors::{Error};
+
+/// How long to wait for the rest of an escape sequence after ESC, before taking it as the Escape key.
+const ESCAPE_SEQUENCE_MILLIS: u64 = 30;
+const TTY: &str = "/dev/tty";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Key {
+    Char(char),
+    /// Ctrl with a letter, e.g. `Ctrl('c')`
+    Ctrl(char),
+    Enter,
+    Escape,
+    Backspace,
+    Delete,
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+    Home,
+    End,
+    /// escape sequence that isn't recognised
+    Unknown
+}
+
+/// Puts the text to the clipboard of the terminal with OSC 52 escape sequence. Works over SSH,
+/// but only in terminals supporting it; others silently ignore the sequence.
+pub fn copy_to_clipboard(text: &str) -> Result<(), Error> {
+    write_to_terminal(&format!("\x1b]52;c;{}\x07", base64::encode(text.as_bytes())))
+}
+
+/// Writes straight to the controlling terminal, so that the sequence reaches it with stdout
+/// redirected too. Without one it goes to stdout.
+fn write_to_terminal(sequence: &str) -> Result<(), Error> {
+    stdout().flush()?;
+    match OpenOptions::new().write(true).open(TTY) {
+        Ok(mut tty) => tty.write_all(sequence.as_bytes())?,
+        Err(_) => {
+            print!("{}", sequence);
+            stdout().flush()?;
+        }
+    }
+    Ok(())
+}
+
+/// Clears the clipboard of the terminal `seconds` later from a detached child process, so that
+/// the command returns to the shell at once. The child writes only to the terminal it opens:
+/// it closes stdin, stdout and stderr, so it doesn't hold a pipe the output goes to open.
+/// Call it with no file locks held, the child would keep them for the whole wait.
+#[cfg(unix)]
+pub fn clear_clipboard_after(seconds: u64) -> Result<(), Error> {
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+    use libc;
+    // opened before setsid, which leaves the child without the controlling terminal
+    let mut tty = match OpenOptions::new().write(true).open(TTY) {
+        Ok(tty) => tty,
+        // nothing to clear when there's no terminal
+        Err(_) => return Ok(())
+    };
+    stdout().flush()?;
+    match unsafe { libc::fork() } {
+        -1 => return Err(Error::from(io::Error::last_os_error())),
+        0 => (),
+        _ => return Ok(())
+    }
+    unsafe {
+        for fd in 0..3 {
+            if fd != tty.as_raw_fd() {
+                libc::close(fd);
+            }
+        }
+        libc::setsid();
+    }
+    thread::sleep(Duration::from_secs(seconds));
+    // data that isn't base64 clears the selection
+    let _ = tty.write_all(b"\x1b]52;c;!\x07");
+    unsafe { libc::_exit(0) }
+}
+
+#[cfg(not(unix))]
+pub fn clear_clipboard_after(_seconds: u64) -> Result<(), Error> {
+    Ok(())
+}
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Whether SIGINT or SIGTERM arrived since the screen was entered.
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Terminal prepared for redrawing in place: keys are read one at a time without echo, Ctrl-C comes
+/// as a key instead of killing the process, and the cursor is hidden. Everything is restored when
+/// the screen is dropped, on errors too.
+pub struct Screen {
+    #[cfg(unix)]
+    saved: Option<::libc::termios>,
+    alternate: bool
+}
+
+#[cfg(unix)]
+extern "C" fn on_signal(_signal: ::libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+#[cfg(unix)]
+fn stdin_is_terminal() -> bool {
+    unsafe { ::libc::isatty(0) == 1 }
+}
+
+/// Columns and rows of the terminal on stdout.
+#[cfg(unix)]
+pub fn size() -> Option<(usize, usize)> {
+    use libc;
+    let mut size: libc::winsize = unsafe { ::std::mem::zeroed() };
+    match unsafe { libc::ioctl(1, libc::TIOCGWINSZ, &mut size) } {
+        0 if size.ws_col > 0 && size.ws_row > 0 => Some((size.ws_col as usize, size.ws_row as usize)),
+        _ => None
+    }
+}
+
+#[cfg(not(unix))]
+pub fn size() -> Option<(usize, usize)> {
+    None
+}
+
+/// Key from the bytes read so far, or `None` when more bytes are needed.
+fn decode_key(bytes: &[u8]) -> Option<Key> {
+    if bytes.is_empty() {
+        return None
+    }
+    if bytes[0] == 0x1b {
+        if bytes.len() < 3 {
+            return match bytes.get(1) {
+                None | Some(&b'[') | Some(&b'O') => None,
+                Some(_) => Some(Key::Unknown)
+            }
+        }
+        if bytes[1] != b'[' && bytes[1] != b'O' {
+            return Some(Key::Unknown)
+        }
+        // CSI sequence ends with a byte from 0x40 to 0x7e
+        let last = bytes[bytes.len() - 1];
+        if last < 0x40 || last > 0x7e {
+            return if bytes.len() < 8 { None } else { Some(Key::Unknown) }
+        }
+        return Some(match &bytes[2..] {
+            b"A" => Key::Up,
+            b"B" => Key::Down,
+            b"H" | b"1~" | b"7~" => Key::Home,
+            b"F" | b"4~" | b"8~" => Key::End,
+            b"3~" => Key::Delete,
+            b"5~" => Key::PageUp,
+            b"6~" => Key::PageDown,
+            _ => Key::Unknown
+        })
+    }
+    match bytes[0] {
+        b'\r' | b'\n' => return Some(Key::Enter),
+        0x7f | 0x08 => return Some(Key::Backspace),
+        c if c < 0x20 => return Some(Key::Ctrl((c + b'a' - 1) as char)),
+        _ => ()
+    }
+    match ::std::str::from_utf8(bytes) {
+        Ok(text) => text.chars().next().map(Key::Char),
+        // incomplete UTF-8 sequence of a typed character
+        Err(_) if bytes.len() < 4 => None,
+        Err(_) => Some(Key::Unknown)
+    }
+}
+
+#[cfg(unix)]
+impl Screen {
+    /// Full screen: the alternate screen buffer of the terminal is used, and the content
+    /// that was on the terminal before comes back when the screen is dropped.
+    pub fn enter_full() -> Result<Screen, Error> {
+        let mut screen = Screen::enter()?;
+        print!("\x1b[?1049h\x1b[H\x1b[2J");
+        stdout().flush()?;
+        screen.alternate = true;
+        Ok(screen)
+    }
+
+    pub fn enter() -> Result<Screen, Error> {
+        use libc;
+        unsafe {
+            libc::signal(libc::SIGINT, on_signal as libc::sighandler_t);
+            libc::signal(libc::SIGTERM, on_signal as libc::sighandler_t);
+        }
+        let saved = match stdin_is_terminal() {
+            false => None,
+            true => unsafe {
+                let mut saved: libc::termios = ::std::mem::zeroed();
+                if libc::tcgetattr(0, &mut saved) != 0 {
+                    return Err(Error::FileError)
+                }
+                let mut raw = saved;
+                raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG);
+                raw.c_cc[libc::VMIN] = 1;
+                raw.c_cc[libc::VTIME] = 0;
+                libc::tcsetattr(0, libc::TCSANOW, &raw);
+                Some(saved)
+            }
+        };
+        print!("\x1b[?25l");
+        stdout().flush()?;
+        Ok(Screen { saved, alternate: false })
+    }
+
+    /// Waits up to `timeout` for a key and returns it. Without a terminal on stdin it only sleeps.
+    pub fn wait_key(&self, timeout: Duration) -> Result<Option<Key>, Error> {
+        if self.saved.is_none() {
+            thread::sleep(timeout);
+            return Ok(None)
+        }
+        let mut bytes = Vec::new();
+        let mut wait = timeout;
+        loop {
+            match read_byte(wait) {
+                Some(byte) => bytes.push(byte),
+                // ESC alone is the Escape key, unfinished sequence is dropped
+                None if bytes == [0x1b] => return Ok(Some(Key::Escape)),
+                None if bytes.is_empty() => return Ok(None),
+                None => return Ok(Some(Key::Unknown))
+            }
+            if let Some(key) = decode_key(&bytes) {
+                return Ok(Some(key))
+            }
+            wait = Duration::from_millis(ESCAPE_SEQUENCE_MILLIS);
+        }
+    }
+}
+
+/// Byte from stdin, `None` on timeout or when interrupted by a signal.
+#[cfg(unix)]
+fn read_byte(timeout: Duration) -> Option<u8> {
+    use libc;
+    // rounded up, as waiting 0 ms for the rest of a millisecond would spin until it's over
+    let millis = timeout.as_secs() * 1000 + (timeout.subsec_nanos() as u64 + 999_999) / 1_000_000;
+    let mut fd = libc::pollfd { fd: 0, events: libc::POLLIN, revents: 0 };
+    if unsafe { libc::poll(&mut fd, 1, millis as libc::c_int) } <= 0 {
+        return None
+    }
+    let mut byte = [0u8; 1];
+    match unsafe { libc::read(0, byte.as_mut_ptr() as *mut libc::c_void, 1) } {
+        1 => Some(byte[0]),
+        _ => None
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Screen {
+    fn drop(&mut self) {
+        use libc;
+        if let Some(ref saved) = self.saved {
+            unsafe { libc::tcsetattr(0, libc::TCSANOW, saved); }
+        }
+        unsafe {
+            libc::signal(libc::SIGINT, libc::SIG_DFL);
+            libc::signal(libc::SIGTERM, libc::SIG_DFL);
+        }
+        if self.alternate {
+            print!("\x1b[?1049l");
+        }
+        print!("\x1b[?25h");
+        let _ = stdout().flush();
+    }
+}
+
+#[cfg(not(unix))]
+impl Screen {
+    pub fn enter_full() -> Result<Screen, Error> {
+        Screen::enter()
+    }
+
+    pub fn enter() -> Result<Screen, Error> {
+        Ok(Screen { alternate: false })
+    }
+
+    pub fn wait_key(&self, timeout: Duration) -> Result<Option<Key>, Error> {
+        thread::sleep(timeout);
+        Ok(None)
+    }
+}
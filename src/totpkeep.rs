@@ -1,33 +1,393 @@
-use std::fs::File;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::time::{SystemTime, UNIX_EPOCH};
 use oath::{totp_raw_custom_time as totpfn, HashType};
-use crpt::{encrypt, decrypt};
+use aegis::{self, AegisEntry};
+use agent;
+use body::{self, Field};
+use crpt::{encrypt, decrypt, decrypt_with_key, key_context, read_kdf, FileKey};
+use kdf::{Kdf};
+use migration::{self, MigrationAccount};
+use otpauth::{percent_decode, OtpAuthUri};
 use errors::{Error};
+use password::{self, Password};
+use qr;
+use selector::{self, Candidate};
+use storage;
 use table;
+use terminal;
+use tui::{self, Action, Browser};
+use view::{self, Column, ViewColumn};
+use utils::zero;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Algorithm {
+    SHA1,
+    SHA256,
+    SHA512
+}
+
+impl Algorithm {
+    pub fn parse(s: &str) -> Result<Algorithm, Error> {
+        match &s.trim().to_uppercase()[..] {
+            "SHA1" => Ok(Algorithm::SHA1),
+            "SHA256" => Ok(Algorithm::SHA256),
+            "SHA512" => Ok(Algorithm::SHA512),
+            _ => Err(Error::WrongServiceRecordParameters)
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Algorithm::SHA1 => "SHA1",
+            Algorithm::SHA256 => "SHA256",
+            Algorithm::SHA512 => "SHA512"
+        }
+    }
+
+    fn hash_type(&self) -> HashType {
+        match *self {
+            Algorithm::SHA1 => HashType::SHA1,
+            Algorithm::SHA256 => HashType::SHA256,
+            Algorithm::SHA512 => HashType::SHA512
+        }
+    }
+}
+
+/// TOTP generation parameters of the single record (RFC 6238).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OtpParams {
+    pub digits: u32,
+    pub period: u64,
+    pub algorithm: Algorithm,
+    pub epoch: u64
+}
+
+impl OtpParams {
+    pub fn default() -> Self {
+        OtpParams { digits: 6, period: 30, algorithm: Algorithm::SHA1, epoch: 0 }
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.digits < 6 || self.digits > 8 || self.period == 0 {
+            return Err(Error::WrongServiceRecordParameters)
+        }
+        Ok(())
+    }
+}
+
+/// Output of `list`. Table is for people, the rest for scripts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ListFormat {
+    Table,
+    Json,
+    Tsv,
+    Plain
+}
+
+/// Changes of record metadata given to `add` and `edit`. `None` and empty lists leave the value as it is.
+#[derive(Debug, Default)]
+pub struct RecordChanges<'a> {
+    pub account: Option<&'a str>,
+    /// empty string removes the issuer
+    pub issuer: Option<&'a str>,
+    pub notes: Option<&'a str>,
+    pub tags: Vec<&'a str>,
+    pub untags: Vec<&'a str>
+}
+
+/// TOTP codes depend on time, HOTP codes on the counter kept in the record (RFC 4226).
+/// HOTP `counter` is the value the next code is generated for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OtpKind {
+    Totp,
+    Hotp { counter: u64 }
+}
 
 #[derive(Debug)]
 struct ServiceRecord {
-    name: String,
-    secret: Vec<u8>
+    /// random, lowercase hex; does not change when the record is renamed or moved
+    id: String,
+    account: String,
+    issuer: Option<String>,
+    secret: Vec<u8>,
+    params: OtpParams,
+    kind: OtpKind,
+    notes: String,
+    tags: Vec<String>,
+    /// Unix time, 0 when unknown (records written before these were kept) or never used
+    created: u64,
+    modified: u64,
+    last_used: u64,
+    /// fields written by a newer version, kept as they are
+    unknown: Vec<Field>
 }
 
+// top level fields of the body
+const BODY_RECORD: u64 = 1;
+
+// record fields, see "Body encoding" in README
+const RECORD_ID: u64 = 1;
+const RECORD_ACCOUNT: u64 = 2;
+const RECORD_ISSUER: u64 = 3;
+const RECORD_SECRET: u64 = 4;
+const RECORD_DIGITS: u64 = 5;
+const RECORD_PERIOD: u64 = 6;
+const RECORD_ALGORITHM: u64 = 7;
+const RECORD_EPOCH: u64 = 8;
+const RECORD_TYPE: u64 = 9;
+const RECORD_COUNTER: u64 = 10;
+const RECORD_NOTES: u64 = 11;
+const RECORD_TAG: u64 = 12;
+const RECORD_CREATED: u64 = 13;
+const RECORD_MODIFIED: u64 = 14;
+const RECORD_LAST_USED: u64 = 15;
+
+const TYPE_TOTP: u64 = 0;
+const TYPE_HOTP: u64 = 1;
+
 impl ServiceRecord {
-    fn new(name: &str, code: &str) -> Result<ServiceRecord, Error> {
-        use base32:: {decode, Alphabet};
-        let secret = match decode(
-            Alphabet::RFC4648 { padding: false },
-            &code.trim().to_uppercase().replace(" ", "").replace("\t", "")
-        ) {
-            Some(bytes) => bytes,
-            None => return Err(Error::WrongServiceRecordData)
+    fn new(account: &str, code: &str, params: OtpParams) -> Result<ServiceRecord, Error> {
+        let secret = decode_secret(code).ok_or(Error::WrongServiceRecordData)?;
+        params.validate()?;
+        // there are no codes before T0, a future one is a mistake
+        if params.epoch > unix_time() {
+            return Err(Error::WrongServiceRecordParameters)
+        }
+        ServiceRecord::with_secret(String::from(account), None, secret, params, OtpKind::Totp)
+    }
+
+    fn with_secret(account: String, issuer: Option<String>, secret: Vec<u8>, params: OtpParams, kind: OtpKind) -> Result<ServiceRecord, Error> {
+        let now = unix_time();
+        Ok(ServiceRecord {
+            id: new_id()?,
+            account,
+            issuer,
+            secret,
+            params,
+            kind,
+            notes: String::new(),
+            tags: Vec::new(),
+            created: now,
+            modified: now,
+            last_used: 0,
+            unknown: Vec::new()
+        })
+    }
+
+    /// Applies the changes; tags are trimmed, kept unique and empty ones are ignored.
+    fn change(&mut self, changes: &RecordChanges) {
+        if let Some(account) = changes.account {
+            self.account = account.to_string();
+        }
+        if let Some(issuer) = changes.issuer {
+            self.issuer = match issuer.trim() {
+                "" => None,
+                issuer => Some(issuer.to_string())
+            };
+        }
+        if let Some(notes) = changes.notes {
+            self.notes = notes.to_string();
+        }
+        for tag in changes.tags.iter().map(|t| t.trim()) {
+            if !tag.is_empty() && !self.tags.iter().any(|t| t == tag) {
+                self.tags.push(tag.to_string());
+            }
+        }
+        self.tags.retain(|t| !changes.untags.iter().any(|u| u.trim() == t));
+        self.modified = unix_time();
+    }
+
+    fn from_uri(uri: &str) -> Result<ServiceRecord, Error> {
+        let parsed = OtpAuthUri::parse(uri)?;
+        let mut record = ServiceRecord::new(&parsed.account, &parsed.secret, parsed.params)?;
+        record.issuer = parsed.issuer;
+        record.kind = parsed.kind;
+        Ok(record)
+    }
+
+    fn from_migration(account: MigrationAccount) -> Result<ServiceRecord, Error> {
+        ServiceRecord::with_secret(account.name, account.issuer, account.secret, account.params, account.kind)
+    }
+
+    fn from_aegis(entry: AegisEntry) -> Result<ServiceRecord, Error> {
+        ServiceRecord::with_secret(entry.name, entry.issuer, entry.secret, entry.params, entry.kind)
+    }
+
+    fn to_aegis(&self) -> AegisEntry {
+        AegisEntry {
+            name: self.account.clone(),
+            issuer: self.issuer.clone(),
+            secret: self.secret.clone(),
+            params: self.params,
+            kind: self.kind
+        }
+    }
+
+    fn to_migration(&self) -> MigrationAccount {
+        MigrationAccount {
+            name: self.account.clone(),
+            issuer: self.issuer.clone(),
+            secret: self.secret.clone(),
+            params: self.params,
+            kind: self.kind
+        }
+    }
+
+    fn to_uri(&self) -> OtpAuthUri {
+        OtpAuthUri {
+            issuer: self.issuer.clone(),
+            account: self.account.clone(),
+            secret: self.marshall_secret(),
+            params: self.params,
+            kind: self.kind
+        }
+    }
+
+    fn display_name(&self) -> String {
+        match self.issuer {
+            Some(ref issuer) => format!("{}:{}", issuer, self.account),
+            None => self.account.clone()
+        }
+    }
+
+    /// Parses `account\0SECRET[\0digits\0period\0algorithm\0epoch[\0issuer[\0type\0counter[\0id
+    /// [\0notes\0tags\0created\0modified\0last_used]]]]]` line of the body format used by earlier versions.
+    /// Notes and comma separated tags are percent-encoded. Lines written before parameters were introduced
+    /// get the defaults. Lines without ID get one derived from `position` and the name, see `legacy_id`.
+    /// Error is the reason the line can't be read.
+    fn from_line(line: &[u8], position: usize) -> Result<ServiceRecord, String> {
+        let line = ::std::str::from_utf8(line).map_err(|_| String::from("not valid UTF-8"))?;
+        let mut parts = line.split("\0");
+        let account = parts.next().unwrap_or("");
+        let secret = parts.next().ok_or_else(|| String::from("no secret"))?;
+        let secret = decode_secret(secret).ok_or_else(|| String::from("secret is not valid base32"))?;
+        let params = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(digits), Some(period), Some(algorithm), Some(epoch)) => OtpParams {
+                digits: parse_number(digits, "digits")? as u32,
+                period: parse_number(period, "period")?,
+                algorithm: Algorithm::parse(algorithm).map_err(|_| format!("unknown algorithm {:?}", algorithm))?,
+                epoch: parse_number(epoch, "T0")?
+            },
+            _ => OtpParams::default()
         };
-        Ok(ServiceRecord{ name:  String::from(name), secret })
+        params.validate().map_err(|_| String::from("unsupported number of digits or period"))?;
+        let issuer = parts.next().and_then(|i| if i.is_empty() { None } else { Some(i.to_string()) });
+        let kind = match (parts.next(), parts.next()) {
+            (Some("hotp"), Some(counter)) => OtpKind::Hotp { counter: parse_number(counter, "HOTP counter")? },
+            _ => OtpKind::Totp
+        };
+        let mut record = ServiceRecord::with_secret(account.to_string(), issuer, secret, params, kind)
+            .map_err(|e| e.to_string())?;
+        record.id = match parts.next().filter(|id| !id.is_empty()) {
+            Some(id) => id.to_string(),
+            None => legacy_id(position, &record.account, record.issuer.as_ref().map(|i| &i[..]))
+        };
+        record.notes = percent_decode(parts.next().unwrap_or(""), false)
+            .map_err(|_| String::from("notes are not properly encoded"))?;
+        record.tags = parts.next().unwrap_or("").split(',')
+            .filter(|t| !t.is_empty())
+            .map(|t| percent_decode(t, false).map_err(|_| String::from("tags are not properly encoded")))
+            .collect::<Result<Vec<String>, String>>()?;
+        record.created = parts.next().map_or(Ok(0), |t| parse_number(t, "creation time"))?;
+        record.modified = parts.next().map_or(Ok(0), |t| parse_number(t, "modification time"))?;
+        record.last_used = parts.next().map_or(Ok(0), |t| parse_number(t, "last use time"))?;
+        Ok(record)
+    }
+
+    /// Record fields of the body. Defaults and empty values are left out.
+    fn to_fields(&self) -> Vec<Field> {
+        let mut fields = vec![
+            Field::text(RECORD_ID, &self.id),
+            Field::text(RECORD_ACCOUNT, &self.account)
+        ];
+        if let Some(ref issuer) = self.issuer {
+            fields.push(Field::text(RECORD_ISSUER, issuer));
+        }
+        fields.push(Field::bytes(RECORD_SECRET, &self.secret));
+        fields.push(Field::number(RECORD_DIGITS, self.params.digits as u64));
+        fields.push(Field::number(RECORD_PERIOD, self.params.period));
+        fields.push(Field::text(RECORD_ALGORITHM, self.params.algorithm.name()));
+        fields.push(Field::number(RECORD_EPOCH, self.params.epoch));
+        if let OtpKind::Hotp { counter } = self.kind {
+            fields.push(Field::number(RECORD_TYPE, TYPE_HOTP));
+            fields.push(Field::number(RECORD_COUNTER, counter));
+        }
+        if !self.notes.is_empty() {
+            fields.push(Field::text(RECORD_NOTES, &self.notes));
+        }
+        for tag in &self.tags {
+            fields.push(Field::text(RECORD_TAG, tag));
+        }
+        for &(tag, time) in [(RECORD_CREATED, self.created), (RECORD_MODIFIED, self.modified),
+                             (RECORD_LAST_USED, self.last_used)].iter() {
+            if time != 0 {
+                fields.push(Field::number(tag, time));
+            }
+        }
+        fields.extend(self.unknown.iter().cloned());
+        fields
     }
 
-    fn from(s: &str) -> Result<ServiceRecord, Error> {
-        let mut parts = s.split("\0");
-        ServiceRecord::new(parts.next().unwrap(), parts.next().unwrap())
+    /// Record from its body fields. Account and secret are required, the rest has defaults.
+    /// Error is the reason the record can't be read.
+    fn from_fields(fields: Vec<Field>) -> Result<ServiceRecord, String> {
+        let mut id = None;
+        let mut account = None;
+        let mut issuer = None;
+        let mut secret = None;
+        let mut params = OtpParams::default();
+        let mut hotp = false;
+        let mut counter = 0;
+        let mut notes = String::new();
+        let mut tags = Vec::new();
+        let mut times = [0u64; 3];
+        let mut unknown = Vec::new();
+        for field in fields {
+            let text = |what: &str| field.to_text().map_err(|_| format!("{} is not valid UTF-8", what));
+            let number = |what: &str| field.to_number().map_err(|_| format!("{} is not a valid number", what));
+            match field.tag {
+                RECORD_ID => id = Some(text("ID")?),
+                RECORD_ACCOUNT => account = Some(text("account")?),
+                RECORD_ISSUER => issuer = Some(text("issuer")?),
+                RECORD_SECRET => secret = Some(field.value.clone()),
+                RECORD_DIGITS => params.digits = number("digits")? as u32,
+                RECORD_PERIOD => params.period = number("period")?,
+                RECORD_ALGORITHM => {
+                    let algorithm = text("algorithm")?;
+                    params.algorithm = Algorithm::parse(&algorithm)
+                        .map_err(|_| format!("unknown algorithm {:?}", algorithm))?;
+                },
+                RECORD_EPOCH => params.epoch = number("T0")?,
+                RECORD_TYPE => hotp = match number("type")? {
+                    TYPE_TOTP => false,
+                    TYPE_HOTP => true,
+                    other => return Err(format!("unknown type {}", other))
+                },
+                RECORD_COUNTER => counter = number("HOTP counter")?,
+                RECORD_NOTES => notes = text("notes")?,
+                RECORD_TAG => tags.push(text("tag")?),
+                RECORD_CREATED => times[0] = number("creation time")?,
+                RECORD_MODIFIED => times[1] = number("modification time")?,
+                RECORD_LAST_USED => times[2] = number("last use time")?,
+                _ => unknown.push(field.clone())
+            }
+        }
+        params.validate().map_err(|_| String::from("unsupported number of digits or period"))?;
+        let kind = if hotp { OtpKind::Hotp { counter } } else { OtpKind::Totp };
+        let account = account.ok_or_else(|| String::from("no account"))?;
+        let secret = secret.ok_or_else(|| String::from("no secret"))?;
+        let mut record = ServiceRecord::with_secret(account, issuer, secret, params, kind)
+            .map_err(|e| e.to_string())?;
+        if let Some(id) = id {
+            record.id = id;
+        }
+        record.notes = notes;
+        record.tags = tags;
+        record.created = times[0];
+        record.modified = times[1];
+        record.last_used = times[2];
+        record.unknown = unknown;
+        Ok(record)
     }
 
     fn marshall_secret(&self) -> String {
@@ -36,6 +396,23 @@ impl ServiceRecord {
     }
 }
 
+impl Drop for ServiceRecord {
+    fn drop(&mut self) {
+        zero(&mut self.secret[..]);
+    }
+}
+
+/// Decrypted records along with the KDF the file is protected with,
+/// so that saving keeps the KDF chosen for the file.
+struct Registry {
+    records: Vec<ServiceRecord>,
+    kdf: Kdf,
+    /// Key the file was decrypted with. Reused on save while KDF is unchanged.
+    key: Option<FileKey>,
+    /// top level body fields written by a newer version, kept as they are
+    unknown: Vec<Field>
+}
+
 fn default_registry_path() -> Result<PathBuf, Error> {
     use std::env;
     match env::home_dir() {
@@ -59,131 +436,1204 @@ fn get_path(file: Option<&str>) -> Result<PathBuf, Error> {
     }
 }
 
-fn totp(secret: &[u8], time: u64) -> String {
-    let value = totpfn(secret, 6, 0, 30, time, &HashType::SHA1);
-    format!("{:06}", value)
+fn decode_secret(code: &str) -> Option<Vec<u8>> {
+    use base32::{decode, Alphabet};
+    decode(Alphabet::RFC4648 { padding: false }, &code.trim().to_uppercase().replace(" ", "").replace("\t", ""))
+}
+
+fn parse_number(value: &str, what: &str) -> Result<u64, String> {
+    value.parse().map_err(|_| format!("{} is not a number", what))
+}
+
+fn new_id() -> Result<String, Error> {
+    use rand::{OsRng, Rng};
+    use rustc_serialize::hex::ToHex;
+    let mut id = [0u8; 8];
+    OsRng::new()?.fill_bytes(&mut id);
+    Ok(id.to_hex())
+}
+
+/// ID of a record stored without one by an earlier version, the same on every read of the file
+/// until it is saved with the ID. The secret is left out, as IDs are shown.
+fn legacy_id(position: usize, account: &str, issuer: Option<&str>) -> String {
+    use crypto::digest::Digest;
+    use crypto::sha2::Sha256;
+    use rustc_serialize::hex::ToHex;
+    let mut hasher = Sha256::new();
+    hasher.input_str(&format!("totpkeep legacy record\0{}\0{}\0{}", position, issuer.unwrap_or(""), account));
+    let mut hash = [0u8; 32];
+    hasher.result(&mut hash);
+    hash[..8].to_hex()
+}
+
+/// Position of the record chosen by the selector, see `selector::resolve`.
+fn select(registry: &Registry, record: &str) -> Result<usize, Error> {
+    select_match(registry, record).map(|(index, _)| index)
+}
+
+fn select_match(registry: &Registry, record: &str) -> Result<(usize, selector::Match), Error> {
+    let candidates = registry.records.iter()
+        .map(|r| Candidate { id: &r.id, name: &r.account, display_name: r.display_name(), tags: &r.tags })
+        .collect::<Vec<Candidate>>();
+    selector::resolve_match(record, &candidates)
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// "5m ago", "2h 10m ago" or "3d 4h ago".
+fn format_age(now: u64, time: u64) -> String {
+    match now.saturating_sub(time) / 60 {
+        0 => String::from("just now"),
+        m if m < 60 => format!("{}m ago", m),
+        m if m < 24 * 60 => format!("{}h {}m ago", m / 60, m % 60),
+        m => format!("{}d {}h ago", m / (24 * 60), m / 60 % 24)
+    }
+}
+
+/// No code ("-") before T0, when the time step count would be negative.
+fn totp(record: &ServiceRecord, time: u64) -> String {
+    let params = &record.params;
+    if time < params.epoch {
+        return String::from("-")
+    }
+    let value = totpfn(&record.secret, params.digits, params.epoch, params.period, time,
+                       &params.algorithm.hash_type());
+    format!("{:0w$}", value, w = params.digits as usize)
+}
+
+/// TOTP with T0 = 0 and 1 second step is HOTP with time used as the counter,
+/// which gives HOTP with any of the supported hash algorithms.
+fn hotp(record: &ServiceRecord, counter: u64) -> String {
+    let params = &record.params;
+    let value = totpfn(&record.secret, params.digits, 0, 1, counter, &params.algorithm.hash_type());
+    format!("{:0w$}", value, w = params.digits as usize)
+}
+
+fn seconds_remaining(record: &ServiceRecord, now: u64) -> u64 {
+    let period = record.params.period;
+    period - now.saturating_sub(record.params.epoch) % period
+}
+
+/// Previous, current and next TOTP codes. HOTP records have none, as generating one consumes it.
+fn codes(record: &ServiceRecord, now: u64) -> Option<[String; 3]> {
+    let period = record.params.period;
+    match record.kind {
+        OtpKind::Totp => Some([totp(record, now.saturating_sub(period)), totp(record, now), totp(record, now + period)]),
+        OtpKind::Hotp { .. } => None
+    }
+}
+
+/// JSON array with an object per record. HOTP records have `null` codes and their counter instead.
+fn registry_json(registry: &[ServiceRecord], now: u64) -> String {
+    use std::collections::BTreeMap;
+    use rustc_serialize::json::{self, Json};
+    let records = registry.iter().enumerate().map(|(i, record)| {
+        let mut object = BTreeMap::new();
+        let code = |c: &Option<[String; 3]>, i: usize| c.as_ref().map_or(Json::Null, |c| Json::String(c[i].clone()));
+        let codes = codes(record, now);
+        object.insert("index".to_string(), Json::U64(i as u64 + 1));
+        object.insert("id".to_string(), Json::String(record.id.clone()));
+        object.insert("name".to_string(), Json::String(record.display_name()));
+        object.insert("account".to_string(), Json::String(record.account.clone()));
+        object.insert("issuer".to_string(), record.issuer.clone().map_or(Json::Null, Json::String));
+        object.insert("notes".to_string(), Json::String(record.notes.clone()));
+        object.insert("tags".to_string(), Json::Array(record.tags.iter().map(|t| Json::String(t.clone())).collect()));
+        let timestamp = |t: u64| if t == 0 { Json::Null } else { Json::U64(t) };
+        object.insert("created".to_string(), timestamp(record.created));
+        object.insert("modified".to_string(), timestamp(record.modified));
+        object.insert("last_used".to_string(), timestamp(record.last_used));
+        object.insert("previous".to_string(), code(&codes, 0));
+        object.insert("current".to_string(), code(&codes, 1));
+        object.insert("next".to_string(), code(&codes, 2));
+        match record.kind {
+            OtpKind::Totp => {
+                object.insert("type".to_string(), Json::String("totp".to_string()));
+                object.insert("period".to_string(), Json::U64(record.params.period));
+                object.insert("remaining".to_string(), Json::U64(seconds_remaining(record, now)));
+            },
+            OtpKind::Hotp { counter } => {
+                object.insert("type".to_string(), Json::String("hotp".to_string()));
+                object.insert("counter".to_string(), Json::U64(counter));
+            }
+        }
+        Json::Object(object)
+    }).collect();
+    format!("{}\n", json::as_pretty_json(&Json::Array(records)))
+}
+
+/// Header line and a line per record with tab separated index, name, previous, current and next codes,
+/// seconds remaining, period, ID, comma separated tags, notes and Unix times of creation, modification
+/// and last use. Tabs and line breaks in text are replaced by spaces, missing values are "-".
+fn registry_tsv(registry: &[ServiceRecord], now: u64) -> String {
+    let clean = |s: &str| s.replace(|c| c == '\t' || c == '\n' || c == '\r', " ");
+    let timestamp = |t: u64| if t == 0 { String::from("-") } else { t.to_string() };
+    let mut text = String::from("index\tname\tprevious\tcurrent\tnext\tremaining\tperiod\t\
+                                 id\ttags\tnotes\tcreated\tmodified\tlast_used\n");
+    for (i, record) in registry.iter().enumerate() {
+        let codes = codes(record, now).unwrap_or([String::from("-"), String::from("-"), String::from("-")]);
+        let (remaining, period) = match record.kind {
+            OtpKind::Totp => (seconds_remaining(record, now).to_string(), record.params.period.to_string()),
+            OtpKind::Hotp { .. } => (String::from("-"), String::from("-"))
+        };
+        text.push_str(&format!("{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n", i + 1, clean(&record.display_name()),
+                               codes[0], codes[1], codes[2], remaining, period, record.id,
+                               clean(&record.tags.join(",")), clean(&record.notes),
+                               timestamp(record.created), timestamp(record.modified), timestamp(record.last_used)));
+    }
+    text
+}
+
+/// `name: current code` per record, HOTP records show their counter as in the table.
+fn registry_plain(registry: &[ServiceRecord], now: u64) -> String {
+    let mut text = String::new();
+    for record in registry {
+        let current = match record.kind {
+            OtpKind::Totp => totp(record, now),
+            OtpKind::Hotp { counter } => format!("#{}", counter)
+        };
+        text.push_str(&format!("{}: {}\n", record.display_name(), current));
+    }
+    text
+}
+
+/// First line of the notes, shortened to keep the table narrow.
+fn notes_summary(notes: &str) -> String {
+    const MAX_CHARS: usize = 30;
+    let line = notes.lines().next().unwrap_or("");
+    match line.chars().count() > MAX_CHARS || notes.lines().count() > 1 {
+        true => format!("{}...", line.chars().take(MAX_CHARS).collect::<String>()),
+        false => line.to_string()
+    }
+}
+
+/// `long` adds ID, tags, notes and last use columns.
+fn display_registry(registry: &[ServiceRecord], symbols: &table::TableSymbols, long: bool) {
+    display_columns(registry, symbols, &view::default_columns(long));
 }
 
-fn display_registry(registry: &[ServiceRecord], symbols: &table::TableSymbols) {
+fn display_columns(registry: &[ServiceRecord], symbols: &table::TableSymbols, columns: &[ViewColumn]) {
     use std::io::{stdout, Write};
+    stdout().write(render_registry(registry, symbols, columns, unix_time()).as_bytes());
+}
+
+/// Text of the column for the record at `index`, with codes at `now`.
+fn column_value(column: Column, index: usize, record: &ServiceRecord, now: u64) -> String {
+    // showing HOTP code would not consume it, see `next`
+    if let OtpKind::Hotp { counter } = record.kind {
+        match column {
+            Column::Current => return format!("#{}", counter),
+            Column::Previous | Column::Next | Column::SecondsLeft | Column::Period => return String::from("-"),
+            _ => ()
+        }
+    }
+    match column {
+        Column::Index => (index + 1).to_string(),
+        Column::Name => record.display_name(),
+        Column::Issuer => record.issuer.clone().unwrap_or_default(),
+        Column::Account => record.account.clone(),
+        Column::Id => record.id.chars().take(8).collect(),
+        Column::Tags => record.tags.join(","),
+        Column::Notes => notes_summary(&record.notes),
+        Column::Previous => totp(record, now.saturating_sub(record.params.period)),
+        Column::Current => totp(record, now),
+        Column::Next => totp(record, now + record.params.period),
+        Column::SecondsLeft => format!("{}s", seconds_remaining(record, now)),
+        Column::Period => format!("{}s", record.params.period),
+        Column::Algorithm => record.params.algorithm.name().to_string(),
+        Column::LastUsed => match record.last_used {
+            0 => String::from("never"),
+            used => format_age(now, used)
+        }
+    }
+}
+
+/// Columns of the progress bar, one per second of the default period.
+const PROGRESS_WIDTH: u64 = 30;
+
+/// Table of the records with codes at `now`, followed by the progress bar when it has codes.
+fn render_registry(registry: &[ServiceRecord], symbols: &table::TableSymbols, columns: &[ViewColumn], now: u64) -> String {
     use std::iter::repeat;
 
     let mut table = table::Table::new();
-    table.add_field("name");
-    table.add_field("prev");
-    table.add_field("curr");
-    table.add_field("next");
-    table.append_header("name", "Name");
-    table.append_header("prev", "Previous");
-    table.append_header("curr", "Current");
-    table.append_header("next", "Next");
-
-    let names = ["name", "prev", "curr", "next"];
-    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-    for record in registry {
-        let codes = [now - 30, now, now + 30].iter()
-            .map(|t| totp(&record.secret, *t))
-            .collect::<Vec<String>>();
-
-        table.add_row(&names, &[
-            record.name.as_ref(),
-            codes[0].as_ref(),
-            codes[1].as_ref(),
-            codes[2].as_ref()]
-        );
+    let names = columns.iter().map(|c| c.column.name()).collect::<Vec<_>>();
+    for column in columns {
+        table.add_field(column.column.name());
+        table.append_header(column.column.name(), column.column.header());
+    }
+    for (index, record) in registry.iter().enumerate() {
+        let row = columns.iter().map(|c| column_value(c.column, index, record, now)).collect::<Vec<_>>();
+        table.add_row(&names, &row.iter().map(|c| c.as_ref()).collect::<Vec<&str>>());
     }
 
     let mut formatter = table::StringTableFormatter::new(symbols);
-    formatter.rows.push(table::RowFormat{
-        padding: 1,
-        alignment: table::Alignment::Left,
-        row_numbers: false
-    });
-    formatter.rows.push(table::RowFormat::default());
-    formatter.rows.push(table::RowFormat::default());
-    formatter.rows.push(table::RowFormat::default());
-
-    stdout().write(formatter.format(&table).as_bytes());
-
-    let secs_pass = match now % 30 {
-        0 => 30,
+    formatter.max_width = terminal::size().map(|(columns, _)| columns);
+    for column in columns {
+        formatter.rows.push(table::RowFormat{
+            padding: 1,
+            alignment: column.alignment,
+            row_numbers: false,
+            shrink: column.column.shrinks()
+        });
+    }
+
+    let mut text = formatter.format(&table);
+    if !columns.iter().any(|c| c.column.is_code()) {
+        return text
+    }
+    // a single bar can't tell records with different periods or epochs apart
+    let mut periods = registry.iter()
+        .filter(|r| r.kind == OtpKind::Totp)
+        .map(|r| (r.params.period, seconds_remaining(r, now)));
+    let (period, remaining) = match periods.next() {
+        Some(first) if periods.all(|other| other == first) => first,
+        _ => return text
+    };
+
+    let secs_pass = match period - remaining {
+        0 => period,
         n => n
     };
-    let mut progress = String::with_capacity(33);
-    progress.push_str(symbols.progress_left());
-    repeat(symbols.progress_middle()).take(secs_pass as usize).for_each(|ch| progress.push_str(ch));
-    repeat(" ").take((30 - secs_pass) as usize).for_each(|ch| progress.push_str(ch));
-    progress.push_str(symbols.progress_right());
-    progress.push_str("\n");
-    stdout().write(progress.as_bytes());
+    let filled = (secs_pass * PROGRESS_WIDTH / period) as usize;
+    text.push_str(symbols.progress_left());
+    repeat(symbols.progress_middle()).take(filled).for_each(|ch| text.push_str(ch));
+    repeat(" ").take(PROGRESS_WIDTH as usize - filled).for_each(|ch| text.push_str(ch));
+    text.push_str(symbols.progress_right());
+    text.push_str("\n");
+    text
 }
 
-fn load_registry(file: Option<&str>, password: &str, ignore_not_exist: bool) -> Result<Vec<ServiceRecord>, Error> {
-    use std::io::{BufReader, BufRead, Read, Cursor};
+fn load_registry(file: Option<&str>, password: &Password, ignore_not_exist: bool) -> Result<Registry, Error> {
     let path = get_path(file)?;
     if !path.exists() {
         if ignore_not_exist {
-            return Ok(Vec::new());
+            return Ok(Registry { records: Vec::new(), kdf: Kdf::default(), key: None, unknown: Vec::new() });
         }
         return Err(Error::FileNotFound)
     }
+    read_registry(&path, password, true)
+}
+
+/// Key held by the unlock agent for the content, when `TOTPKEEP_AGENT_SOCK` is set.
+fn agent_key(encrypted: &Vec<u8>) -> Option<FileKey> {
+    match key_context(encrypted) {
+        Ok(Some(context)) => agent::cached_key(&context[..]),
+        _ => None
+    }
+}
 
-    let file = File::open(path)?;
-    let mut reader = BufReader::new(file);
-    let mut encrypted: Vec<u8> = Vec::new();
-    reader.read_to_end(&mut encrypted)?;
-    let decrypted: Vec<u8> = decrypt(&encrypted, password)?;
-    let cursor = Cursor::new(decrypted);
-    let registry = cursor
-        .lines()
-        .map(|el| el.unwrap())
-        .map(|l| ServiceRecord::from(&l).unwrap()).collect::<Vec<ServiceRecord>>();
-    return Ok(registry);
+fn read_registry(path: &Path, password: &Password, use_agent: bool) -> Result<Registry, Error> {
+    let encrypted = storage::read(path)?;
+    parse_registry(&encrypted, password, use_agent)
 }
 
-fn save_registry(file: Option<&str>, password: &str, registry: &Vec<ServiceRecord>) -> Result<(), Error> {
-    use std::io::{Cursor, Write};
-    let mut buff = Cursor::new(Vec::new());
-    for record in registry {
-        buff.write(record.name.as_bytes());
-        buff.write(b"\0");
-        buff.write(record.marshall_secret().as_bytes());
-        buff.write(b"\n")?;
+fn decrypt_registry(encrypted: &Vec<u8>, password: &Password, use_agent: bool) -> Result<(Vec<u8>, Option<FileKey>), Error> {
+    let cached = match use_agent {
+        true => agent_key(encrypted)
+            .and_then(|key| decrypt_with_key(encrypted, &key).ok().map(|body| (body, Some(key)))),
+        false => None
     };
-    let encrypted = encrypt(&buff.into_inner(), password);
+    match cached {
+        Some(result) => Ok(result),
+        None => decrypt(encrypted, &password.get()?)
+    }
+}
+
+/// Decrypted body read record by record, so that a damaged record doesn't hide the others.
+struct Body {
+    /// record or the reason it can't be read
+    records: Vec<Result<ServiceRecord, String>>,
+    unknown: Vec<Field>,
+    /// why the rest of the body, after the records, can't be read
+    damaged_rest: Option<String>
+}
+
+fn read_body(decrypted: &[u8]) -> Result<Body, Error> {
+    if body::is_structured(decrypted) {
+        let (fields, damaged) = body::decode(decrypted)?;
+        let mut records = Vec::new();
+        let mut unknown = Vec::new();
+        for field in fields {
+            match field.tag {
+                BODY_RECORD => records.push(match body::read_fields(&field.value) {
+                    (fields, None) => ServiceRecord::from_fields(fields),
+                    (_, Some(offset)) => Err(format!("field at byte {} of the record is cut off", offset))
+                }),
+                _ => unknown.push(field)
+            }
+        }
+        let damaged_rest = damaged.map(|offset| format!("data from byte {} of the body is cut off", offset));
+        return Ok(Body { records, unknown, damaged_rest })
+    }
+    // line format of earlier versions, saved in the current one next time
+    let records = decrypted.split(|&b| b == b'\n')
+        .filter(|line| !line.is_empty())
+        .enumerate()
+        .map(|(position, line)| ServiceRecord::from_line(line, position))
+        .collect();
+    Ok(Body { records, unknown: Vec::new(), damaged_rest: None })
+}
+
+fn parse_registry(encrypted: &Vec<u8>, password: &Password, use_agent: bool) -> Result<Registry, Error> {
+    let (decrypted, key) = decrypt_registry(encrypted, password, use_agent)?;
+    registry_from_body(encrypted, &decrypted, key)
+}
+
+fn registry_from_body(encrypted: &Vec<u8>, decrypted: &[u8], key: Option<FileKey>) -> Result<Registry, Error> {
+    let body = read_body(decrypted)?;
+    let mut records = Vec::new();
+    for (i, record) in body.records.into_iter().enumerate() {
+        records.push(record.map_err(|reason| Error::CorruptedRecord(i + 1, reason))?);
+    }
+    if let Some(reason) = body.damaged_rest {
+        return Err(Error::CorruptedRecord(records.len() + 1, reason))
+    }
+    Ok(Registry { records, kdf: read_kdf(encrypted)?, key, unknown: body.unknown })
+}
+
+/// The file as it is now, read with the key of `registry` when it has one, so that the KDF doesn't run again.
+fn reload_registry(path: &Path, registry: &Registry, password: &Password) -> Result<Registry, Error> {
+    let encrypted = storage::read(path)?;
+    match registry.key {
+        Some(ref key) => {
+            let key = FileKey::from_context(&key.context(), key.bytes())?;
+            let decrypted = decrypt_with_key(&encrypted, &key)?;
+            registry_from_body(&encrypted, &decrypted, Some(key))
+        },
+        None => parse_registry(&encrypted, password, true)
+    }
+}
+
+/// Lock to hold for the whole read-modify-write cycle, so that concurrent invocations
+/// do not overwrite each other's changes.
+fn lock_registry(file: Option<&str>) -> Result<storage::FileLock, Error> {
+    storage::lock(&get_path(file)?)
+}
+
+fn save_registry(file: Option<&str>, password: &Password, registry: &Registry) -> Result<(), Error> {
     let path = get_path(file)?;
-    let mut file = File::create(path)?;
-    file.write_all(&encrypted[..])?;
+    write_registry(&path, password, registry)
+}
+
+/// Records followed by the unknown top level fields, see "Body encoding" in README.
+fn encode_body(registry: &Registry) -> Vec<u8> {
+    let mut fields = registry.records.iter()
+        .map(|r| Field::bytes(BODY_RECORD, &body::encode_fields(&r.to_fields())))
+        .collect::<Vec<Field>>();
+    fields.extend(registry.unknown.iter().cloned());
+    body::encode(&fields)
+}
+
+fn write_registry(path: &Path, password: &Password, registry: &Registry) -> Result<(), Error> {
+    storage::write_atomic(path, &encrypt_registry(password, registry)?[..])
+}
+
+/// Saves bookkeeping changes, like last use time, without a backup generation.
+fn touch_registry(file: Option<&str>, password: &Password, registry: &Registry) -> Result<(), Error> {
+    storage::write_atomic_no_backup(&get_path(file)?, &encrypt_registry(password, registry)?[..])
+}
+
+fn encrypt_registry(password: &Password, registry: &Registry) -> Result<Vec<u8>, Error> {
+    let derived;
+    let key = match registry.key {
+        Some(ref key) if key.kdf == registry.kdf => key,
+        _ => {
+            derived = FileKey::derive(&password.get()?, registry.kdf)?;
+            &derived
+        }
+    };
+    Ok(encrypt(&encode_body(registry), key))
+}
+
+pub fn add_service(name: &str, code: &str, params: OtpParams, kind: OtpKind, changes: &RecordChanges, password: &Password, file: Option<&str>, symbols: &table::TableSymbols) -> Result<(), Error> {
+    let _lock = lock_registry(file)?;
+    let mut registry = load_registry(file, password, true)?;
+    let mut new_record = ServiceRecord::new(name, code, params)?;
+    new_record.kind = kind;
+    new_record.change(changes);
+    registry.records.push(new_record);
+    save_registry(file, password, &registry)?;
+    display_registry(&registry.records, symbols, false);
     Ok(())
 }
 
-pub fn add_service(name: &str, code: &str, password: &str, file: Option<&str>, symbols: &table::TableSymbols) -> Result<(), Error> {
+/// Adds records from `otpauth://` and `otpauth-migration://` URIs.
+/// Records with the secret already present in the file are skipped, so repeated imports are harmless.
+pub fn import_uris(uris: &[&str], password: &Password, file: Option<&str>, symbols: &table::TableSymbols) -> Result<(), Error> {
+    let _lock = lock_registry(file)?;
     let mut registry = load_registry(file, password, true)?;
-    let new_record = ServiceRecord::new(name, code)?;
-    registry.push(new_record);
+    let mut imported: Vec<ServiceRecord> = Vec::new();
+    for uri in uris {
+        if uri.trim().to_lowercase().starts_with("otpauth-migration:") {
+            for account in migration::parse(uri)? {
+                imported.push(ServiceRecord::from_migration(account)?);
+            }
+        } else {
+            imported.push(ServiceRecord::from_uri(uri)?);
+        }
+    }
+    append_new(&mut registry, imported);
     save_registry(file, password, &registry)?;
-    display_registry(&registry, symbols);
+    display_registry(&registry.records, symbols, false);
     Ok(())
 }
 
-pub fn remove_service(index: u16, password: &str, file: Option<&str>, symbols: &table::TableSymbols) -> Result<(), Error> {
+fn append_new(registry: &mut Registry, imported: Vec<ServiceRecord>) {
+    for record in imported {
+        if registry.records.iter().any(|r| r.secret == record.secret) {
+            eprintln!("Skipped {}: the same secret is already stored", record.display_name());
+            continue
+        }
+        registry.records.push(record);
+    }
+}
+
+/// Adds records from plain or encrypted Aegis vault export.
+/// `backup_password` is asked on the terminal when the export is encrypted and it is not given.
+pub fn import_aegis(path: &str, backup_password: Option<&str>, password: &Password, file: Option<&str>, symbols: &table::TableSymbols) -> Result<(), Error> {
+    let content = storage::read(Path::new(path)).map_err(|_| Error::FileNotFound)?;
+    let (entries, skipped) = aegis::parse(&content, || password::other_password(backup_password, "Aegis backup password"))?;
+    for name in skipped {
+        eprintln!("Skipped {}: only TOTP and HOTP entries are supported", name);
+    }
+    let _lock = lock_registry(file)?;
     let mut registry = load_registry(file, password, true)?;
-    // TODO range check
-    registry.remove((index - 1) as usize);
+    let imported = entries.into_iter().map(ServiceRecord::from_aegis).collect::<Result<Vec<_>, Error>>()?;
+    append_new(&mut registry, imported);
+    save_registry(file, password, &registry)?;
+    display_registry(&registry.records, symbols, false);
+    Ok(())
+}
+
+/// Writes all records to a new Aegis vault file, encrypted when `backup_password` is set.
+pub fn export_aegis(path: &str, backup_password: Option<&str>, password: &Password, file: Option<&str>) -> Result<(), Error> {
+    let registry = load_registry(file, password, false)?;
+    let entries = registry.records.iter().map(|r| r.to_aegis()).collect::<Vec<AegisEntry>>();
+    storage::write_new(Path::new(path), &aegis::write(&entries, backup_password)?)
+}
+
+/// Prints `otpauth://` URI per record, or `otpauth-migration://` URIs for Google Authenticator
+/// when `google` is set. Records it can't represent are left out with a note.
+pub fn export_uris(record: Option<&str>, google: bool, password: &Password, file: Option<&str>) -> Result<(), Error> {
+    let registry = load_registry(file, password, false)?;
+    let selected: Vec<&ServiceRecord> = match record {
+        Some(record) => vec![&registry.records[select(&registry, record)?]],
+        None => registry.records.iter().collect()
+    };
+    if google {
+        let mut accounts = Vec::new();
+        for record in selected {
+            match MigrationAccount::is_exportable(&record.params) {
+                true => accounts.push(record.to_migration()),
+                false => eprintln!("Skipped {}: period, T0 or digits not supported by Google Authenticator",
+                                   record.display_name())
+            }
+        }
+        for uri in migration::to_uris(&accounts)? {
+            println!("{}", uri);
+        }
+        return Ok(())
+    }
+    for record in selected {
+        println!("{}", record.to_uri());
+    }
+    Ok(())
+}
+
+/// Asks on the terminal, anything but "y" or "yes" is refusal.
+fn confirm(question: &str) -> Result<bool, Error> {
+    use std::io::{stdin, stdout, Write};
+    print!("{} [y/N] ", question);
+    stdout().flush()?;
+    let mut answer = String::new();
+    stdin().read_line(&mut answer)?;
+    let answer = answer.trim().to_lowercase();
+    Ok(answer == "y" || answer == "yes")
+}
+
+/// Shows `otpauth://` URI of the record as QR code on the terminal, or writes it to a new
+/// PNG or SVG file chosen by `output` extension. Needs confirmation, as it reveals the secret.
+pub fn show_qr(record: &str, output: Option<&str>, ascii: bool, invert: bool, password: &Password, file: Option<&str>) -> Result<(), Error> {
+    let registry = load_registry(file, password, true)?;
+    let index = select(&registry, record)?;
+    let extension = output.map(|path| Path::new(path).extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase())
+        .unwrap_or_default());
+    match extension {
+        Some(ref e) if e != "png" && e != "svg" => return Err(Error::UnsupportedImageFormat),
+        _ => ()
+    }
+    let record = &registry.records[index];
+    if !confirm(&format!("QR code reveals the secret of {}. Show it?", record.display_name()))? {
+        return Err(Error::NotConfirmed)
+    }
+    let modules = qr::encode(&record.to_uri().to_string())?;
+    match (output, extension) {
+        (Some(path), Some(ref e)) if e == "png" => storage::write_new(Path::new(path), &qr::to_png(&modules)?),
+        (Some(path), _) => storage::write_new(Path::new(path), qr::to_svg(&modules).as_bytes()),
+        (None, _) => {
+            print!("{}", qr::render_text(&modules, ascii, invert));
+            Ok(())
+        }
+    }
+}
+
+/// Changes account, issuer, notes or tags of the record. Secret and parameters stay as they are.
+pub fn edit_service(record: &str, changes: &RecordChanges, password: &Password, file: Option<&str>, symbols: &table::TableSymbols) -> Result<(), Error> {
+    let _lock = lock_registry(file)?;
+    let mut registry = load_registry(file, password, false)?;
+    let index = select(&registry, record)?;
+    registry.records[index].change(changes);
+    save_registry(file, password, &registry)?;
+    display_registry(&registry.records, symbols, true);
+    Ok(())
+}
+
+/// Removes the record. Unless it is selected by ID, index or exact name the removal has to be
+/// confirmed, which is asked before the file is locked.
+pub fn remove_service(record: &str, password: &Password, file: Option<&str>, symbols: &table::TableSymbols) -> Result<(), Error> {
+    let path = get_path(file)?;
+    let registry = load_registry(file, password, false)?;
+    let id = {
+        let (index, kind) = select_match(&registry, record)?;
+        let record = &registry.records[index];
+        if !kind.is_exact() && !confirm(&format!("Remove {} (id:{})?", record.display_name(), record.id))? {
+            return Err(Error::NotConfirmed)
+        }
+        record.id.clone()
+    };
+    let _lock = lock_registry(file)?;
+    let mut registry = reload_registry(&path, &registry, password)?;
+    let index = registry.records.iter().position(|r| r.id == id).ok_or(Error::RecordNotFound)?;
+    registry.records.remove(index);
     save_registry(file, password, &registry)?;
-    display_registry(&registry, symbols);
-    // TODO take into account empty registry
+    match registry.records.is_empty() {
+        true => println!("No records left"),
+        false => display_registry(&registry.records, symbols, false)
+    }
     Ok(())
 }
 
-pub fn list_services(password: &str, file: Option<&str>, symbols: &table::TableSymbols) -> Result<(), Error> {
+/// `columns` are those of the table, other formats have their own.
+pub fn list_services(format: ListFormat, columns: &[ViewColumn], password: &Password, file: Option<&str>, symbols: &table::TableSymbols) -> Result<(), Error> {
     let registry = load_registry(file, password, true)?;
-    display_registry(&registry, symbols);
+    let now = unix_time();
+    match format {
+        ListFormat::Table => display_columns(&registry.records, symbols, columns),
+        ListFormat::Json => print!("{}", registry_json(&registry.records, now)),
+        ListFormat::Tsv => print!("{}", registry_tsv(&registry.records, now)),
+        ListFormat::Plain => print!("{}", registry_plain(&registry.records, now))
+    }
+    Ok(())
+}
+
+/// Shows the table and redraws it in place every second, with codes of every record changing when its
+/// period rolls over, until a key is pressed, Ctrl-C or `timeout` seconds pass.
+pub fn watch(timeout: Option<u64>, columns: &[ViewColumn], password: &Password, file: Option<&str>, symbols: &table::TableSymbols) -> Result<(), Error> {
+    use std::io::{stdout, Write};
+    use std::time::{Duration, Instant};
+    let registry = load_registry(file, password, false)?;
+    let screen = terminal::Screen::enter()?;
+    let started = Instant::now();
+    let mut drawn_lines = 0;
+    loop {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let text = render_registry(&registry.records, symbols, columns, now.as_secs());
+        if drawn_lines > 0 {
+            // back to the first line of the previous table
+            print!("\x1b[{}A", drawn_lines);
+        }
+        print!("\r\x1b[J{}", text);
+        stdout().flush()?;
+        drawn_lines = text.lines().count();
+
+        let mut wait = Duration::from_secs(1) - Duration::new(0, now.subsec_nanos());
+        if let Some(timeout) = timeout {
+            match Duration::from_secs(timeout).checked_sub(started.elapsed()) {
+                Some(left) if left > Duration::from_secs(0) => wait = wait.min(left),
+                _ => return Ok(())
+            }
+        }
+        if screen.wait_key(wait)?.is_some() || terminal::interrupted() {
+            return Ok(())
+        }
+    }
+}
+
+/// Full screen browser of the records, see `tui::Browser`. Records are decrypted only in memory and dropped,
+/// secrets zeroed, after `idle` seconds without a key; the password is asked again then. 0 disables that.
+pub fn browse(idle: u64, password: &Password, file: Option<&str>, symbols: &table::TableSymbols) -> Result<(), Error> {
+    use std::time::Duration;
+    let path = get_path(file)?;
+    let mut session = Some(open_session(&path, password)?);
+    password.forget();
+    let screen = terminal::Screen::enter_full()?;
+    let mut browser = Browser::new(symbols);
+    let idle = if idle == 0 { None } else { Some(Duration::from_secs(idle)) };
+    loop {
+        let action = browser.next_action(&screen, idle, |now| match session {
+            Some(ref registry) => browser_rows(registry, now),
+            None => Vec::new()
+        })?;
+        let result = match action {
+            Action::Quit => return Ok(()),
+            Action::Lock => {
+                session = None;
+                browser.lock();
+                Ok(())
+            },
+            Action::Unlock(text) => unlock_session(&path, &text).map(|registry| {
+                session = Some(registry);
+                browser.unlocked();
+            }),
+            action => match session {
+                Some(ref mut registry) => browser_change(&path, registry, action).and_then(|(message, clear)| {
+                    browser.status(&message);
+                    // forked only now, after the lock of the file is released
+                    match clear {
+                        Some(seconds) => terminal::clear_clipboard_after(seconds),
+                        None => Ok(())
+                    }
+                }),
+                None => Ok(())
+            }
+        };
+        if let Err(err) = result {
+            browser.status(&format!("Error: {}", err));
+        }
+    }
+}
+
+/// Registry with the key to save and re-read it without the password.
+fn open_session(path: &Path, password: &Password) -> Result<Registry, Error> {
+    let mut registry = match path.exists() {
+        true => read_registry(path, password, true)?,
+        false => Registry { records: Vec::new(), kdf: Kdf::default(), key: None, unknown: Vec::new() }
+    };
+    if registry.key.is_none() {
+        registry.key = Some(FileKey::derive(&password.get()?, registry.kdf)?);
+    }
+    Ok(registry)
+}
+
+/// Registry for the password typed to unlock the browser. The agent is never asked,
+/// as the point of the lock is that the password is typed again.
+fn unlock_session(path: &Path, password: &str) -> Result<Registry, Error> {
+    if password.is_empty() {
+        return Err(Error::EmptyPassword)
+    }
+    let mut registry = match path.exists() {
+        true => {
+            let encrypted = storage::read(path)?;
+            let (decrypted, key) = decrypt(&encrypted, password)?;
+            registry_from_body(&encrypted, &decrypted, key)?
+        },
+        false => Registry { records: Vec::new(), kdf: Kdf::default(), key: None, unknown: Vec::new() }
+    };
+    if registry.key.is_none() {
+        registry.key = Some(FileKey::derive(password, registry.kdf)?);
+    }
+    Ok(registry)
+}
+
+fn browser_rows(registry: &Registry, now: u64) -> Vec<tui::Row> {
+    registry.records.iter().enumerate().map(|(i, record)| {
+        let (code, left) = match record.kind {
+            OtpKind::Totp => (totp(record, now), format!("{}s", seconds_remaining(record, now))),
+            // the code is only generated when copied, as that consumes it
+            OtpKind::Hotp { counter } => (String::from("-"), format!("#{}", counter))
+        };
+        tui::Row {
+            index: i + 1,
+            id: record.id.clone(),
+            account: record.account.clone(),
+            name: record.display_name(),
+            code,
+            left
+        }
+    }).collect()
+}
+
+/// Applies the action to the file as it is now, under the lock, so that changes made meanwhile
+/// by other invocations are kept. Returns the message for the status line and, when a code
+/// was copied, the seconds after which the clipboard is to be cleared.
+fn browser_change(path: &Path, registry: &mut Registry, action: Action) -> Result<(String, Option<u64>), Error> {
+    let _lock = storage::lock(path)?;
+    let mut current = {
+        let key = registry.key.as_ref().ok_or(Error::WrongPassword)?;
+        let key = FileKey::from_context(&key.context(), key.bytes())?;
+        match path.exists() {
+            true => {
+                let encrypted = storage::read(path)?;
+                let decrypted = decrypt_with_key(&encrypted, &key)?;
+                registry_from_body(&encrypted, &decrypted, Some(key))?
+            },
+            false => Registry { records: Vec::new(), kdf: registry.kdf, key: Some(key), unknown: Vec::new() }
+        }
+    };
+    let find = |records: &[ServiceRecord], id: &str| records.iter().position(|r| r.id == id).ok_or(Error::RecordNotFound);
+    let (message, backup, clear) = match action {
+        Action::Add { account, secret } => {
+            current.records.push(ServiceRecord::new(account.trim(), &secret, OtpParams::default())?);
+            (format!("Added {}", account.trim()), true, None)
+        },
+        Action::Rename { id, account } => {
+            let index = find(&current.records, &id)?;
+            current.records[index].change(&RecordChanges { account: Some(&account), ..RecordChanges::default() });
+            (format!("Renamed to {}", current.records[index].display_name()), true, None)
+        },
+        Action::Remove { id } => {
+            let index = find(&current.records, &id)?;
+            let removed = current.records.remove(index);
+            (format!("Removed {}", removed.display_name()), true, None)
+        },
+        Action::Move { id, up } => {
+            let index = find(&current.records, &id)?;
+            let other = match up {
+                true if index > 0 => index - 1,
+                false if index + 1 < current.records.len() => index + 1,
+                _ => return Ok((String::new(), None))
+            };
+            current.records.swap(index, other);
+            (String::new(), true, None)
+        },
+        Action::Copy { id } => {
+            let index = find(&current.records, &id)?;
+            let now = unix_time();
+            let record = &mut current.records[index];
+            record.last_used = now;
+            // kept on the clipboard while valid, HOTP code for one period as by `get --copy`
+            let (code, backup, valid) = match record.kind {
+                OtpKind::Totp => (totp(record, now), false, seconds_remaining(record, now)),
+                OtpKind::Hotp { counter } => {
+                    record.kind = OtpKind::Hotp { counter: counter + 1 };
+                    (hotp(record, counter), true, record.params.period)
+                }
+            };
+            terminal::copy_to_clipboard(&code)?;
+            (format!("Copied code of {}, cleared in {}s", record.display_name(), valid), backup, Some(valid))
+        },
+        Action::Quit | Action::Lock | Action::Unlock(_) => return Ok((String::new(), None))
+    };
+    let encrypted = encrypt(&encode_body(&current), current.key.as_ref().unwrap());
+    match backup {
+        true => storage::write_atomic(path, &encrypted)?,
+        false => storage::write_atomic_no_backup(path, &encrypted)?
+    }
+    *registry = current;
+    Ok((message, clear))
+}
+
+/// Prints only the current code. For HOTP record that is the next code, and its counter is advanced.
+/// Last use time of the record is updated.
+pub fn get_code(record: &str, copy: Option<u64>, password: &Password, file: Option<&str>) -> Result<(), Error> {
+    // the lock and the records are gone before the process clearing the clipboard is forked
+    let (code, valid) = {
+        let mut registry = load_registry(file, password, false)?;
+        let index = select(&registry, record)?;
+        let now = unix_time();
+        match registry.records[index].kind {
+            // HOTP code doesn't expire, it is kept on the clipboard for one period.
+            // The counter must be saved before the code is shown, so it is read again under the lock.
+            OtpKind::Hotp { .. } => {
+                let _lock = lock_registry(file)?;
+                let mut registry = load_registry(file, password, false)?;
+                let index = select(&registry, record)?;
+                let period = registry.records[index].params.period;
+                (next_code(&mut registry, index, password, file)?, period)
+            },
+            OtpKind::Totp => {
+                let valid = seconds_remaining(&registry.records[index], now);
+                match copy {
+                    Some(min_left) if valid < min_left => return Err(Error::CodeExpiresSoon(valid)),
+                    _ => ()
+                }
+                let code = totp(&registry.records[index], now);
+                record_use(&registry.records[index].id, now, password, file);
+                (code, valid)
+            }
+        }
+    };
+    match copy {
+        None => println!("{}", code),
+        Some(_) => {
+            terminal::copy_to_clipboard(&code)?;
+            println!("Copied, the clipboard is cleared in {} seconds", valid);
+            terminal::clear_clipboard_after(valid)?;
+        }
+    }
+    Ok(())
+}
+
+/// Saves the last use time of the record when it can be done right away. Read-only files
+/// and files locked by another process keep the old time, the code is shown anyway.
+fn record_use(id: &str, now: u64, password: &Password, file: Option<&str>) {
+    let _lock = match get_path(file).and_then(|path| storage::lock_nowait(&path)) {
+        Ok(lock) => lock,
+        Err(_) => return
+    };
+    // read again, the file may have changed since the code was computed
+    let mut registry = match load_registry(file, password, false) {
+        Ok(registry) => registry,
+        Err(_) => return
+    };
+    if let Some(record) = registry.records.iter_mut().find(|r| r.id == id) {
+        record.last_used = now;
+        let _ = touch_registry(file, password, &registry);
+    }
+}
+
+/// Re-encrypts the file with the new password, fresh salt and nonce.
+/// KDF of the file is replaced when `kdf` is given.
+/// New content replaces the file only after it is decrypted back successfully.
+pub fn change_password(old_pass: &Password, new_pass: &str, kdf: Option<Kdf>, file: Option<&str>) -> Result<(), Error> {
+    let path = get_path(file)?;
+    if !path.exists() {
+        return Err(Error::FileNotFound)
+    }
+    let _lock = storage::lock(&path)?;
+    // old password is always required, even when the agent holds the key
+    let mut registry = read_registry(&path, old_pass, false)?;
+    if let Some(kdf) = kdf {
+        registry.kdf = kdf;
+    }
+    registry.key = None;
+    let new_pass = Password::new(Some(new_pass), None);
+
+    let encrypted = encrypt_registry(&new_pass, &registry)?;
+    let written = parse_registry(&encrypted, &new_pass, false)
+        .map_err(|_| Error::RecryptVerificationFailed)?;
+    if written.kdf != registry.kdf ||
+        encode_body(&written) != encode_body(&registry) {
+        return Err(Error::RecryptVerificationFailed)
+    }
+    storage::write_atomic(&path, &encrypted[..])
+}
+
+/// Hands the key of the file to the unlock agent, so that following commands do not need the password.
+/// Files in older formats are converted first, since only the current format keys are reusable.
+pub fn unlock(password: &Password, file: Option<&str>) -> Result<(), Error> {
+    let path = get_path(file)?;
+    if !path.exists() {
+        return Err(Error::FileNotFound)
+    }
+    let _lock = storage::lock(&path)?;
+    let mut registry = read_registry(&path, password, false)?;
+    if registry.key.is_none() {
+        registry.key = Some(FileKey::derive(&password.get()?, registry.kdf)?);
+        write_registry(&path, password, &registry)?;
+    }
+    agent::add_key(registry.key.as_ref().unwrap())
+}
+
+pub fn lock() -> Result<(), Error> {
+    agent::lock()
+}
+
+pub fn list_backups(file: Option<&str>) -> Result<(), Error> {
+    let path = get_path(file)?;
+    let backups = storage::list_backups(&path);
+    if backups.is_empty() {
+        println!("No backups of {}", path.display());
+    }
+    let now = unix_time();
+    for backup in backups {
+        let age = backup.modified
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|modified| format_age(now, modified.as_secs()))
+            .unwrap_or_else(|| String::from("unknown time"));
+        println!("{}. {}  {} bytes, {}", backup.generation, backup.path.display(), backup.len, age);
+    }
     Ok(())
 }
 
-pub fn change_password(old_pass: &str, new_pass: &str, file: Option<&str>) -> Result<(), Error> {
-    let registry = load_registry(file, old_pass, false)?;
-    save_registry(file, new_pass, &registry)?;
+/// Reads every record of the file and reports each one that can't be read, with the reason, and records
+/// sharing an ID. With `salvage` the readable records are written to that new file, with the same password
+/// and KDF; records sharing an ID get new ones there.
+pub fn check(salvage: Option<&str>, password: &Password, file: Option<&str>) -> Result<(), Error> {
+    let path = get_path(file)?;
+    if !path.exists() {
+        return Err(Error::FileNotFound)
+    }
+    let encrypted = storage::read(&path)?;
+    let (decrypted, key) = decrypt_registry(&encrypted, password, true)?;
+    let body = read_body(&decrypted)?;
+    let total = body.records.len();
+    let mut problems = 0;
+    let mut readable: Vec<(usize, ServiceRecord)> = Vec::new();
+    for (i, record) in body.records.into_iter().enumerate() {
+        match record {
+            Ok(mut record) => {
+                if let Some(&(other, _)) = readable.iter().find(|&&(_, ref r)| r.id == record.id) {
+                    println!("Record {}: same ID as record {}", i + 1, other);
+                    problems += 1;
+                    record.id = new_id()?;
+                }
+                readable.push((i + 1, record));
+            },
+            Err(reason) => {
+                println!("Record {}: {}", i + 1, reason);
+                problems += 1;
+            }
+        }
+    }
+    if let Some(ref reason) = body.damaged_rest {
+        println!("Record {} and the rest of the file: {}", total + 1, reason);
+        problems += 1;
+    }
+    match problems {
+        0 => println!("{} records, no problems found", readable.len()),
+        n => println!("{} problems found, {} records can be read", n, readable.len())
+    }
+    let registry = Registry {
+        records: readable.into_iter().map(|(_, record)| record).collect(),
+        kdf: read_kdf(&encrypted)?,
+        key,
+        unknown: body.unknown
+    };
+    match salvage {
+        Some(salvage) => {
+            storage::write_new(Path::new(salvage), &encrypt_registry(password, &registry)?)?;
+            println!("{} records saved to {}", registry.records.len(), salvage);
+            Ok(())
+        },
+        None if problems > 0 => Err(Error::CorruptedFileContent),
+        None => Ok(())
+    }
+}
+
+/// Replaces the file with the backup generation after checking it decrypts with the password.
+/// The replaced content becomes the most recent backup, so restore can be undone.
+pub fn restore_backup(generation: usize, password: &Password, file: Option<&str>) -> Result<(), Error> {
+    let path = get_path(file)?;
+    let _lock = storage::lock(&path)?;
+    let backup = storage::backup_path(&path, generation);
+    if !backup.exists() {
+        return Err(Error::BackupNotFound)
+    }
+    let encrypted = storage::read(&backup)?;
+    parse_registry(&encrypted, password, false)?;
+    storage::write_atomic(&path, &encrypted[..])
+}
+
+fn hotp_record<'a>(registry: &'a mut Registry, record: &str) -> Result<&'a mut ServiceRecord, Error> {
+    let index = select(registry, record)?;
+    let record = &mut registry.records[index];
+    match record.kind {
+        OtpKind::Hotp { .. } => Ok(record),
+        OtpKind::Totp => Err(Error::NotHotpRecord)
+    }
+}
+
+/// Prints the HOTP code for the current counter and stores the incremented counter
+/// before the code is shown, so the same code is never given out twice.
+pub fn next_hotp(record: &str, password: &Password, file: Option<&str>) -> Result<(), Error> {
+    let _lock = lock_registry(file)?;
+    let mut registry = load_registry(file, password, false)?;
+    let index = select(&registry, record)?;
+    println!("{}", next_code(&mut registry, index, password, file)?);
     Ok(())
 }
+
+/// `next_hotp` for the record at `index` of the registry loaded under the lock.
+fn next_code(registry: &mut Registry, index: usize, password: &Password, file: Option<&str>) -> Result<String, Error> {
+    let code = {
+        let record = &mut registry.records[index];
+        let counter = match record.kind {
+            OtpKind::Hotp { counter } => counter,
+            OtpKind::Totp => return Err(Error::NotHotpRecord)
+        };
+        record.kind = OtpKind::Hotp { counter: counter + 1 };
+        record.last_used = unix_time();
+        hotp(record, counter)
+    };
+    save_registry(file, password, registry)?;
+    Ok(code)
+}
+
+/// Looks for two consecutive codes within `window` counters ahead of the stored one
+/// and moves the counter past them.
+pub fn resync_hotp(record: &str, code1: &str, code2: &str, window: u64, password: &Password, file: Option<&str>) -> Result<(), Error> {
+    let _lock = lock_registry(file)?;
+    let mut registry = load_registry(file, password, false)?;
+    resync(hotp_record(&mut registry, record)?, code1, code2, window)?;
+    save_registry(file, password, &registry)
+}
+
+/// Looks for the two consecutive codes among the next `window` counters of the HOTP record
+/// and moves its counter past them.
+fn resync(record: &mut ServiceRecord, code1: &str, code2: &str, window: u64) -> Result<(), Error> {
+    let counter = match record.kind {
+        OtpKind::Hotp { counter } => counter,
+        OtpKind::Totp => unreachable!()
+    };
+    let found = (counter..counter.saturating_add(window))
+        .find(|&c| hotp(record, c) == code1.trim() && hotp(record, c + 1) == code2.trim());
+    match found {
+        Some(c) => {
+            record.kind = OtpKind::Hotp { counter: c + 2 };
+            record.modified = unix_time();
+            Ok(())
+        },
+        None => Err(Error::HotpResyncFailed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "JBSWY3DPEHPK3PXP";
+
+    /// Number of the first record that can't be read and the reason, as `registry_from_body` reports it.
+    fn corrupted(decrypted: &[u8]) -> (usize, String) {
+        let key = FileKey::derive("password", Kdf::BcryptPbkdf { cost: 2 }).unwrap();
+        let encrypted = encrypt(decrypted, &key);
+        match registry_from_body(&encrypted, decrypted, None) {
+            Err(Error::CorruptedRecord(number, reason)) => (number, reason),
+            Err(other) => panic!("expected CorruptedRecord, got {}", other),
+            Ok(_) => panic!("damaged body was read")
+        }
+    }
+
+    fn record(fields: &[Field]) -> Field {
+        Field::bytes(BODY_RECORD, &body::encode_fields(fields))
+    }
+
+    fn good_record() -> Field {
+        record(&[Field::text(RECORD_ACCOUNT, "good"), Field::bytes(RECORD_SECRET, b"secret")])
+    }
+
+    #[test]
+    fn lines() {
+        let decrypted = format!("good\0{s}\nsecond\0{s}\n", s = SECRET);
+        let body = read_body(decrypted.as_bytes()).unwrap();
+        let records = body.records.into_iter().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(records.iter().map(|r| &r.account[..]).collect::<Vec<_>>(), ["good", "second"]);
+        assert_ne!(records[0].id, records[1].id);
+    }
+
+    #[test]
+    fn line_without_secret() {
+        let decrypted = format!("good\0{}\nno separator\n", SECRET);
+        assert_eq!(corrupted(decrypted.as_bytes()), (2, String::from("no secret")));
+    }
+
+    #[test]
+    fn line_with_bad_base32() {
+        let decrypted = format!("good\0{}\nbad\0not base32!\n", SECRET);
+        assert_eq!(corrupted(decrypted.as_bytes()), (2, String::from("secret is not valid base32")));
+    }
+
+    #[test]
+    fn line_with_bad_utf8() {
+        let mut decrypted = format!("good\0{}\n", SECRET).into_bytes();
+        decrypted.extend_from_slice(b"bad\xff\0");
+        decrypted.extend_from_slice(SECRET.as_bytes());
+        assert_eq!(corrupted(&decrypted), (2, String::from("not valid UTF-8")));
+    }
+
+    #[test]
+    fn record_with_bad_utf8() {
+        let bad = record(&[Field::bytes(RECORD_ACCOUNT, b"bad\xff"), Field::bytes(RECORD_SECRET, b"secret")]);
+        let decrypted = body::encode(&[good_record(), bad]);
+        assert_eq!(corrupted(&decrypted), (2, String::from("account is not valid UTF-8")));
+    }
+
+    #[test]
+    fn record_with_truncated_field() {
+        let mut fields = body::encode_fields(&[Field::text(RECORD_ACCOUNT, "cut")]);
+        // secret announces 10 bytes, only 3 follow
+        fields.extend_from_slice(&[RECORD_SECRET as u8, 10, 1, 2, 3]);
+        let decrypted = body::encode(&[good_record(), good_record(), Field::bytes(BODY_RECORD, &fields)]);
+        assert_eq!(corrupted(&decrypted), (3, String::from("field at byte 5 of the record is cut off")));
+    }
+
+    #[test]
+    fn truncated_body() {
+        let mut decrypted = body::encode(&[good_record(), good_record()]);
+        let cut_at = decrypted.len();
+        decrypted.extend_from_slice(&body::encode_fields(&[good_record()])[..10]);
+        assert_eq!(corrupted(&decrypted),
+                   (3, format!("data from byte {} of the body is cut off", cut_at)));
+    }
+
+    fn progress(periods: &[u64], now: u64) -> Option<String> {
+        let records = periods.iter()
+            .map(|&period| ServiceRecord::new("name", SECRET, OtpParams { period, ..OtpParams::default() }).unwrap())
+            .collect::<Vec<_>>();
+        let text = render_registry(&records, &table::AsciiTableSymbols, &view::default_columns(false), now);
+        text.lines().last().filter(|line| line.starts_with('[')).map(|line| line.to_string())
+    }
+
+    #[test]
+    fn progress_bar_follows_the_period() {
+        let bar = |filled: usize| Some(format!("[{}{}]", "=".repeat(filled), " ".repeat(30 - filled)));
+        assert_eq!(progress(&[30, 30], 61), bar(1));
+        assert_eq!(progress(&[30], 60), bar(30));
+        assert_eq!(progress(&[60], 90), bar(15));
+        // different periods roll over at different times
+        assert_eq!(progress(&[30, 60], 90), None);
+        assert_eq!(progress(&[], 90), None);
+    }
+
+    #[test]
+    fn no_codes_before_epoch() {
+        let now = unix_time();
+        let future = OtpParams { epoch: now + 3600, ..OtpParams::default() };
+        assert!(ServiceRecord::new("name", SECRET, future).is_err());
+        let record = ServiceRecord::new("name", SECRET, OtpParams { epoch: now - 10, ..OtpParams::default() }).unwrap();
+        let codes = codes(&record, now).unwrap();
+        assert_eq!(codes[0], "-");
+        assert_eq!(codes[1].len(), 6);
+        assert_eq!(column_value(Column::Previous, 0, &record, now), "-");
+    }
+
+    /// HOTP record with the secret of RFC 4226 Appendix D.
+    fn rfc4226_record(counter: u64) -> ServiceRecord {
+        ServiceRecord::with_secret(String::from("rfc"), None, b"12345678901234567890".to_vec(),
+                                   OtpParams::default(), OtpKind::Hotp { counter }).unwrap()
+    }
+
+    const RFC4226_CODES: [&str; 10] = ["755224", "287082", "359152", "969429", "338314",
+                                       "254676", "287922", "162583", "399871", "520489"];
+
+    #[test]
+    fn hotp_rfc4226_vectors() {
+        let record = rfc4226_record(0);
+        for (counter, code) in RFC4226_CODES.iter().enumerate() {
+            assert_eq!(hotp(&record, counter as u64), *code);
+        }
+    }
+
+    #[test]
+    fn resync_within_window() {
+        let mut record = rfc4226_record(1);
+        resync(&mut record, RFC4226_CODES[5], RFC4226_CODES[6], 10).unwrap();
+        assert_eq!(record.kind, OtpKind::Hotp { counter: 7 });
+    }
+
+    #[test]
+    fn resync_outside_window() {
+        let mut record = rfc4226_record(1);
+        // codes 5 and 6 are beyond counters 1 .. 4
+        match resync(&mut record, RFC4226_CODES[5], RFC4226_CODES[6], 4) {
+            Err(Error::HotpResyncFailed) => (),
+            other => panic!("expected HotpResyncFailed, got {:?}", other)
+        }
+        assert_eq!(record.kind, OtpKind::Hotp { counter: 1 });
+        // not consecutive
+        assert!(resync(&mut record, RFC4226_CODES[5], RFC4226_CODES[7], 10).is_err());
+    }
+
+    #[test]
+    fn unknown_fields_are_kept() {
+        let extra = Field::text(1000, "from a newer version");
+        let decrypted = body::encode(&[good_record(), extra.clone()]);
+        let body = read_body(&decrypted).unwrap();
+        assert_eq!(body.unknown, vec![extra]);
+        assert!(body.records[0].is_ok());
+    }
+}
@@ -0,0 +1,345 @@
+use std::io::{stdout, Write};
+use std::iter::repeat;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use errors::{Error};
+use selector;
+use table;
+use terminal::{self, Key, Screen};
+use utils::SecretString;
+
+/// Lines of the screen that are not the table: title, status and help.
+const CHROME_LINES: usize = 3;
+const HELP: &str = "↑↓ select  / search  c copy  a add  r rename  d remove  K/J move  l lock  q quit";
+
+/// Record as shown by the browser.
+pub struct Row {
+    /// 1-based position in the file, as used by the selectors
+    pub index: usize,
+    pub id: String,
+    pub account: String,
+    pub name: String,
+    pub code: String,
+    /// countdown of the code, or the counter of HOTP record
+    pub left: String
+}
+
+/// What the browser can't do by itself and asks the caller for.
+#[derive(Debug, Clone)]
+pub enum Action {
+    Quit,
+    /// no key for the idle time, or asked for
+    Lock,
+    Unlock(SecretString),
+    Add { account: String, secret: SecretString },
+    Rename { id: String, account: String },
+    Remove { id: String },
+    Move { id: String, up: bool },
+    Copy { id: String }
+}
+
+#[derive(Debug, Clone)]
+enum Purpose {
+    Unlock,
+    AddAccount,
+    AddSecret { account: String },
+    Rename { id: String, name: String }
+}
+
+enum Mode {
+    Browse,
+    Search,
+    Input { label: &'static str, text: SecretString, hidden: bool, purpose: Purpose },
+    Confirm { question: String, action: Action }
+}
+
+/// Full screen list of the records with live codes, driven by the keyboard.
+/// It only shows rows and collects actions, changes of the records are up to the caller.
+pub struct Browser<'a> {
+    symbols: &'a table::TableSymbols,
+    mode: Mode,
+    filter: String,
+    /// ID of the selected record, kept when records are reloaded or filtered
+    selected: Option<String>,
+    status: String,
+    last_key: Instant
+}
+
+impl<'a> Browser<'a> {
+    pub fn new(symbols: &'a table::TableSymbols) -> Self {
+        Browser {
+            symbols,
+            mode: Mode::Browse,
+            filter: String::new(),
+            selected: None,
+            status: String::new(),
+            last_key: Instant::now()
+        }
+    }
+
+    /// Message shown until the next key.
+    pub fn status(&mut self, message: &str) {
+        self.status = message.to_string();
+    }
+
+    /// Asks for the password, rows are not shown until `unlocked`.
+    pub fn lock(&mut self) {
+        self.mode = Mode::Input { label: "Password", text: SecretString::new(), hidden: true, purpose: Purpose::Unlock };
+    }
+
+    pub fn unlocked(&mut self) {
+        self.mode = Mode::Browse;
+        self.last_key = Instant::now();
+    }
+
+    fn is_locked(&self) -> bool {
+        match self.mode {
+            Mode::Input { purpose: Purpose::Unlock, .. } => true,
+            _ => false
+        }
+    }
+
+    /// Redraws every second until a key asks for an action, the screen is idle for `idle`
+    /// or the process is interrupted. `rows` gives the records with codes at the Unix time.
+    pub fn next_action<F>(&mut self, screen: &Screen, idle: Option<Duration>, rows: F) -> Result<Action, Error>
+        where F: Fn(u64) -> Vec<Row> {
+        loop {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+            let all = rows(now.as_secs());
+            let visible = self.visible(&all);
+            self.keep_selection(&visible);
+            self.draw(&visible, all.len())?;
+            if let Some(idle) = idle {
+                if !self.is_locked() && self.last_key.elapsed() >= idle {
+                    return Ok(Action::Lock)
+                }
+            }
+            let key = screen.wait_key(Duration::from_secs(1) - Duration::new(0, now.subsec_nanos()))?;
+            if terminal::interrupted() {
+                return Ok(Action::Quit)
+            }
+            if let Some(key) = key {
+                self.last_key = Instant::now();
+                self.status.clear();
+                if let Some(action) = self.handle(key, &visible) {
+                    return Ok(action)
+                }
+            }
+        }
+    }
+
+    fn visible<'r>(&self, rows: &'r [Row]) -> Vec<&'r Row> {
+        let filter = self.filter.to_lowercase();
+        rows.iter().filter(|r| selector::fuzzy_match(&filter, &r.name.to_lowercase())).collect()
+    }
+
+    fn keep_selection(&mut self, visible: &[&Row]) {
+        let present = match self.selected {
+            Some(ref id) => visible.iter().any(|r| &r.id == id),
+            None => false
+        };
+        if !present {
+            self.selected = visible.first().map(|r| r.id.clone());
+        }
+    }
+
+    fn position(&self, visible: &[&Row]) -> Option<usize> {
+        match self.selected {
+            Some(ref id) => visible.iter().position(|r| &r.id == id),
+            None => None
+        }
+    }
+
+    fn select_by(&mut self, visible: &[&Row], offset: isize) {
+        if visible.is_empty() {
+            return
+        }
+        let current = self.position(visible).unwrap_or(0) as isize;
+        let last = visible.len() as isize - 1;
+        let target = (current + offset).max(0).min(last);
+        self.selected = Some(visible[target as usize].id.clone());
+    }
+
+    /// Rows of the table that fit on the screen.
+    fn page_len(&self) -> usize {
+        let height = terminal::size().map(|(_, rows)| rows).unwrap_or(24);
+        let header = 1 + self.symbols.print_top() as usize + self.symbols.print_head_bottom() as usize
+            + self.symbols.print_bottom() as usize;
+        let step = 1 + self.symbols.print_mid() as usize;
+        (height.saturating_sub(CHROME_LINES + header) / step).max(1)
+    }
+
+    fn handle(&mut self, key: Key, visible: &[&Row]) -> Option<Action> {
+        if key == Key::Ctrl('c') {
+            return Some(Action::Quit)
+        }
+        let selected = self.position(visible).map(|i| visible[i]);
+        let page = self.page_len() as isize;
+        match ::std::mem::replace(&mut self.mode, Mode::Browse) {
+            Mode::Browse => match key {
+                Key::Up | Key::Char('k') => self.select_by(visible, -1),
+                Key::Down | Key::Char('j') => self.select_by(visible, 1),
+                Key::PageUp => self.select_by(visible, -page),
+                Key::PageDown => self.select_by(visible, page),
+                Key::Home => self.select_by(visible, -(visible.len() as isize)),
+                Key::End => self.select_by(visible, visible.len() as isize),
+                Key::Char('/') => self.mode = Mode::Search,
+                Key::Escape if !self.filter.is_empty() => self.filter.clear(),
+                Key::Escape | Key::Char('q') => return Some(Action::Quit),
+                Key::Char('l') => return Some(Action::Lock),
+                Key::Char('a') => self.mode = Mode::Input {
+                    label: "Account", text: SecretString::new(), hidden: false, purpose: Purpose::AddAccount
+                },
+                Key::Char('r') => if let Some(row) = selected {
+                    self.mode = Mode::Input {
+                        label: "Account",
+                        text: SecretString::from(&row.account[..]),
+                        hidden: false,
+                        purpose: Purpose::Rename { id: row.id.clone(), name: row.name.clone() }
+                    };
+                },
+                Key::Char('d') | Key::Delete => if let Some(row) = selected {
+                    self.mode = Mode::Confirm {
+                        question: format!("Remove {}? Only a backup can bring it back", row.name),
+                        action: Action::Remove { id: row.id.clone() }
+                    };
+                },
+                Key::Char('K') => return selected.map(|row| Action::Move { id: row.id.clone(), up: true }),
+                Key::Char('J') => return selected.map(|row| Action::Move { id: row.id.clone(), up: false }),
+                Key::Char('c') | Key::Enter => return selected.map(|row| Action::Copy { id: row.id.clone() }),
+                _ => ()
+            },
+            Mode::Search => match key {
+                Key::Char(c) => {
+                    self.filter.push(c);
+                    self.mode = Mode::Search;
+                },
+                Key::Backspace => {
+                    self.filter.pop();
+                    self.mode = Mode::Search;
+                },
+                Key::Up | Key::Down => {
+                    self.select_by(visible, if key == Key::Up { -1 } else { 1 });
+                    self.mode = Mode::Search;
+                },
+                Key::Escape => self.filter.clear(),
+                Key::Enter => (),
+                _ => self.mode = Mode::Search
+            },
+            Mode::Input { label, mut text, hidden, purpose } => match key {
+                Key::Enter => return self.submit(text, purpose),
+                Key::Escape => match purpose {
+                    Purpose::Unlock => return Some(Action::Quit),
+                    _ => self.status("Cancelled")
+                },
+                _ => {
+                    match key {
+                        Key::Char(c) => text.push(c),
+                        Key::Backspace => { text.pop(); },
+                        _ => ()
+                    }
+                    self.mode = Mode::Input { label, text, hidden, purpose };
+                }
+            },
+            Mode::Confirm { action, .. } => match key {
+                Key::Char('y') | Key::Char('Y') => return Some(action),
+                _ => self.status("Cancelled")
+            }
+        }
+        None
+    }
+
+    fn submit(&mut self, text: SecretString, purpose: Purpose) -> Option<Action> {
+        match purpose {
+            Purpose::Unlock => {
+                self.lock();
+                return Some(Action::Unlock(text))
+            },
+            Purpose::AddAccount if text.trim().is_empty() => self.status("Cancelled, no account name"),
+            Purpose::AddAccount => self.mode = Mode::Input {
+                label: "Secret", text: SecretString::new(), hidden: true, purpose: Purpose::AddSecret { account: text.to_string() }
+            },
+            Purpose::AddSecret { account } => self.mode = Mode::Confirm {
+                question: format!("Add {}?", account),
+                action: Action::Add { account, secret: text }
+            },
+            Purpose::Rename { id, name } => self.mode = Mode::Confirm {
+                question: format!("Rename {} to {}?", name, &*text),
+                action: Action::Rename { id, account: text.to_string() }
+            }
+        }
+        None
+    }
+
+    fn render_table(&self, visible: &[&Row]) -> String {
+        let names = ["index", "name", "code", "left"];
+        let mut table = table::Table::new();
+        for &(field, header) in [("index", "#"), ("name", "Name"), ("code", "Code"), ("left", "Left")].iter() {
+            table.add_field(field);
+            table.append_header(field, header);
+        }
+        let page = self.page_len();
+        let position = self.position(visible).unwrap_or(0);
+        let first = (position + 1).saturating_sub(page);
+        let shown = visible.iter().skip(first).take(page).collect::<Vec<_>>();
+        for row in shown.iter() {
+            let index = row.index.to_string();
+            table.add_row(&names, &[&index, &row.name, &row.code, &row.left]);
+        }
+        let mut formatter = table::StringTableFormatter::new(self.symbols);
+        formatter.max_width = terminal::size().map(|(columns, _)| columns);
+        for alignment in vec![table::Alignment::Right, table::Alignment::Left, table::Alignment::Middle,
+                              table::Alignment::Right] {
+            let shrink = match alignment {
+                table::Alignment::Left => true,
+                _ => false
+            };
+            formatter.rows.push(table::RowFormat { padding: 1, alignment, row_numbers: false, shrink });
+        }
+        let highlighted = match shown.is_empty() {
+            true => 0..0,
+            false => formatter.data_row_lines(&table, position - first)
+        };
+        formatter.format(&table).lines().enumerate()
+            .map(|(i, line)| match highlighted.start <= i && i < highlighted.end {
+                true => format!("\x1b[7m{}\x1b[0m\x1b[K\r\n", line),
+                false => format!("{}\x1b[K\r\n", line)
+            })
+            .collect()
+    }
+
+    fn draw(&self, visible: &[&Row], total: usize) -> Result<(), Error> {
+        let mut screen = String::from("\x1b[H");
+        let locked = self.is_locked();
+        let title = match (locked, self.filter.is_empty()) {
+            (true, _) => String::from("totpkeep  locked"),
+            (false, true) => format!("totpkeep  {} records", total),
+            (false, false) => format!("totpkeep  {} of {} records match \"{}\"", visible.len(), total, self.filter)
+        };
+        screen.push_str(&format!("\x1b[1m{}\x1b[0m\x1b[K\r\n", title));
+        if !locked {
+            screen.push_str(&self.render_table(visible));
+        }
+        let prompt = match self.mode {
+            Mode::Browse => self.status.clone(),
+            Mode::Search => format!("/{}_", self.filter),
+            Mode::Input { label, ref text, hidden, .. } => match hidden {
+                true => format!("{}: {}_", label, repeat("*").take(text.chars().count()).collect::<String>()),
+                false => format!("{}: {}_", label, &**text)
+            },
+            Mode::Confirm { ref question, .. } => format!("{} [y/N]", question)
+        };
+        let prompt = match (self.status.is_empty(), &self.mode) {
+            (false, &Mode::Browse) | (true, _) => prompt,
+            (false, _) => format!("{}  {}", self.status, prompt)
+        };
+        screen.push_str(&format!("{}\x1b[K\r\n", prompt));
+        if !locked {
+            let width = terminal::size().map(|(columns, _)| columns).unwrap_or(HELP.len());
+            screen.push_str(&table::truncate(HELP, width, self.symbols.ellipsis()));
+        }
+        screen.push_str("\x1b[K\x1b[J");
+        stdout().write_all(screen.as_bytes())?;
+        stdout().flush()?;
+        Ok(())
+    }
+}
@@ -15,4 +15,90 @@ pub fn zero(dst: &mut [u8]) {
     unsafe {
         ptr::write_bytes(dst.as_mut_ptr(), 0, dst.len());
     }
-}
\ No newline at end of file
+}
+
+/// Appends the number as protobuf varint (LEB128): 7 bits per byte, least significant first,
+/// high bit set on all bytes but the last.
+pub fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Varint at `pos`, which is moved past it. `None` when it is cut off or longer than 64 bits.
+pub fn read_varint(data: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut value: u64 = 0;
+    let mut shift = 0;
+    loop {
+        let byte = *data.get(*pos)?;
+        *pos += 1;
+        if shift >= 64 {
+            return None
+        }
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value)
+        }
+        shift += 7;
+    }
+}
+/// Text typed in, such as a password or a secret, zeroed when dropped. Room is reserved up front
+/// and grown by copying into zeroed storage, so no copies are left behind in freed memory.
+#[derive(Clone)]
+pub struct SecretString(String);
+
+const SECRET_CAPACITY: usize = 256;
+
+impl SecretString {
+    pub fn new() -> Self {
+        SecretString(String::with_capacity(SECRET_CAPACITY))
+    }
+
+    pub fn push(&mut self, c: char) {
+        if self.0.len() + c.len_utf8() > self.0.capacity() {
+            let mut grown = String::with_capacity(self.0.capacity() * 2 + c.len_utf8());
+            grown.push_str(&self.0);
+            // the old storage is zeroed when the replaced value is dropped
+            drop(SecretString(::std::mem::replace(&mut self.0, grown)));
+        }
+        self.0.push(c);
+    }
+
+    pub fn pop(&mut self) -> Option<char> {
+        self.0.pop()
+    }
+}
+
+impl<'a> From<&'a str> for SecretString {
+    fn from(text: &'a str) -> Self {
+        let mut secret = SecretString::new();
+        text.chars().for_each(|c| secret.push(c));
+        secret
+    }
+}
+
+impl ::std::ops::Deref for SecretString {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl ::std::fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.write_str("SecretString(..)")
+    }
+}
+
+impl Drop for SecretString {
+    fn drop(&mut self) {
+        // whole capacity, as popped characters stay behind the length
+        unsafe {
+            let bytes = self.0.as_mut_vec();
+            ptr::write_bytes(bytes.as_mut_ptr(), 0, bytes.capacity());
+        }
+    }
+}
@@ -0,0 +1,286 @@
+use std::env;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use errors::{Error};
+use table::Alignment;
+
+/// Environment variable naming the config file to use instead of `$XDG_CONFIG_HOME/totpkeep.conf`.
+pub const CONFIG_ENV: &str = "TOTPKEEP_CONFIG";
+/// View used by `list` and `watch` when the config file has one of this name and no other is asked for.
+const DEFAULT_VIEW: &str = "default";
+const VIEW_PREFIX: &str = "view.";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Column {
+    Index,
+    Name,
+    Issuer,
+    Account,
+    Id,
+    Tags,
+    Notes,
+    Previous,
+    Current,
+    Next,
+    SecondsLeft,
+    Period,
+    Algorithm,
+    LastUsed
+}
+
+const COLUMNS: &[(&str, Column)] = &[
+    ("index", Column::Index),
+    ("name", Column::Name),
+    ("issuer", Column::Issuer),
+    ("account", Column::Account),
+    ("id", Column::Id),
+    ("tags", Column::Tags),
+    ("notes", Column::Notes),
+    ("previous", Column::Previous),
+    ("current", Column::Current),
+    ("next", Column::Next),
+    ("seconds-left", Column::SecondsLeft),
+    ("period", Column::Period),
+    ("algorithm", Column::Algorithm),
+    ("last-used", Column::LastUsed)
+];
+
+impl Column {
+    pub fn name(&self) -> &'static str {
+        COLUMNS.iter().find(|&&(_, column)| column == *self).unwrap().0
+    }
+
+    pub fn header(&self) -> &'static str {
+        match *self {
+            Column::Index => "#",
+            Column::Name => "Name",
+            Column::Issuer => "Issuer",
+            Column::Account => "Account",
+            Column::Id => "ID",
+            Column::Tags => "Tags",
+            Column::Notes => "Notes",
+            Column::Previous => "Previous",
+            Column::Current => "Current",
+            Column::Next => "Next",
+            Column::SecondsLeft => "Left",
+            Column::Period => "Period",
+            Column::Algorithm => "Algorithm",
+            Column::LastUsed => "Last used"
+        }
+    }
+
+    fn alignment(&self) -> Alignment {
+        match *self {
+            Column::Index | Column::SecondsLeft | Column::Period => Alignment::Right,
+            Column::Previous | Column::Current | Column::Next => Alignment::Middle,
+            _ => Alignment::Left
+        }
+    }
+
+    /// Free text, shortened when the table doesn't fit in the terminal.
+    pub fn shrinks(&self) -> bool {
+        match *self {
+            Column::Name | Column::Issuer | Column::Account | Column::Tags | Column::Notes => true,
+            _ => false
+        }
+    }
+
+    /// Whether the column changes with time, so the progress bar is worth showing.
+    pub fn is_code(&self) -> bool {
+        match *self {
+            Column::Previous | Column::Current | Column::Next | Column::SecondsLeft => true,
+            _ => false
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ViewColumn {
+    pub column: Column,
+    pub alignment: Alignment
+}
+
+impl ViewColumn {
+    fn new(column: Column) -> ViewColumn {
+        ViewColumn { column, alignment: column.alignment() }
+    }
+}
+
+/// Columns of the table when none are chosen, `long` adds ID, tags, last use and notes.
+pub fn default_columns(long: bool) -> Vec<ViewColumn> {
+    let mut columns = vec![Column::Name, Column::Previous, Column::Current, Column::Next];
+    if long {
+        columns.extend_from_slice(&[Column::Id, Column::Tags, Column::LastUsed, Column::Notes]);
+    }
+    columns.into_iter().map(ViewColumn::new).collect()
+}
+
+/// Parses comma separated column names, each optionally followed by `:left`, `:middle` or `:right`,
+/// e.g. `index,name,current:right,seconds-left`.
+pub fn parse_columns(spec: &str) -> Result<Vec<ViewColumn>, String> {
+    let mut columns = Vec::new();
+    for item in spec.split(',').map(|item| item.trim()).filter(|item| !item.is_empty()) {
+        let mut parts = item.splitn(2, ':');
+        let name = parts.next().unwrap().trim();
+        let column = match COLUMNS.iter().find(|&&(n, _)| n == name) {
+            Some(&(_, column)) => column,
+            None => return Err(format!("unknown column {:?}, known are {}", name,
+                                       COLUMNS.iter().map(|&(n, _)| n).collect::<Vec<_>>().join(", ")))
+        };
+        let alignment = match parts.next().map(|a| a.trim()) {
+            None => column.alignment(),
+            Some("left") => Alignment::Left,
+            Some("middle") => Alignment::Middle,
+            Some("right") => Alignment::Right,
+            Some(other) => return Err(format!("unknown alignment {:?} of {}, use left, middle or right", other, name))
+        };
+        columns.push(ViewColumn { column, alignment });
+    }
+    match columns.is_empty() {
+        true => Err(String::from("no columns")),
+        false => Ok(columns)
+    }
+}
+
+/// `$TOTPKEEP_CONFIG`, `$XDG_CONFIG_HOME/totpkeep.conf` or `~/.config/totpkeep.conf`.
+fn config_path() -> Result<PathBuf, Error> {
+    if let Some(path) = env::var_os(CONFIG_ENV) {
+        return Ok(PathBuf::from(path))
+    }
+    // relative one is to be ignored, as the XDG base directory specification says
+    if let Some(dir) = env::var_os("XDG_CONFIG_HOME").map(PathBuf::from).filter(|dir| dir.is_absolute()) {
+        return Ok(dir.join("totpkeep.conf"))
+    }
+    match env::home_dir() {
+        Some(home_dir) => Ok([home_dir, PathBuf::from(".config"), PathBuf::from("totpkeep.conf")].iter().collect()),
+        None => Err(Error::NoHomeDirectory)
+    }
+}
+
+/// Views of the config file as name and columns. Missing file has no views.
+fn read_views() -> Result<Vec<(String, Vec<ViewColumn>)>, Error> {
+    let path = config_path()?;
+    if !path.exists() {
+        return Ok(Vec::new())
+    }
+    parse_views(BufReader::new(File::open(&path)?))
+}
+
+/// Lines are `view.<name> = <columns>` as `--columns` takes them, empty lines and lines starting
+/// with `#` are skipped.
+fn parse_views<R: BufRead>(config: R) -> Result<Vec<(String, Vec<ViewColumn>)>, Error> {
+    let mut views = Vec::new();
+    for (number, line) in config.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue
+        }
+        let wrong = |reason: String| Error::WrongConfig(number + 1, reason);
+        let mut parts = line.splitn(2, '=');
+        let key = parts.next().unwrap().trim();
+        let value = parts.next().ok_or_else(|| wrong(String::from("expected key = value")))?;
+        if !key.starts_with(VIEW_PREFIX) || key.len() == VIEW_PREFIX.len() {
+            return Err(wrong(format!("unknown setting {:?}", key)))
+        }
+        let columns = parse_columns(value).map_err(&wrong)?;
+        views.push((key[VIEW_PREFIX.len()..].to_string(), columns));
+    }
+    Ok(views)
+}
+
+/// Columns of the named view, or of the `default` view if there is one when no name is given.
+/// A broken config file fails only the command asking for a view by name, others get a warning
+/// and the default columns.
+pub fn load_view(name: Option<&str>) -> Result<Option<Vec<ViewColumn>>, Error> {
+    select_view(read_views(), name)
+}
+
+fn select_view(views: Result<Vec<(String, Vec<ViewColumn>)>, Error>, name: Option<&str>) -> Result<Option<Vec<ViewColumn>>, Error> {
+    let wanted = name.unwrap_or(DEFAULT_VIEW);
+    let views = match (views, name) {
+        (Ok(views), _) => views,
+        (Err(err), Some(_)) => return Err(err),
+        (Err(err), None) => {
+            eprintln!("Warning: {}, using the default columns", err);
+            return Ok(None)
+        }
+    };
+    // the last one wins, as in most config files
+    match views.into_iter().filter(|&(ref n, _)| n == wanted).last() {
+        Some((_, columns)) => Ok(Some(columns)),
+        None if name.is_some() => Err(Error::ViewNotFound(wanted.to_string())),
+        None => Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn columns(columns: &[ViewColumn]) -> Vec<(Column, Alignment)> {
+        columns.iter().map(|c| (c.column, c.alignment)).collect()
+    }
+
+    fn views(config: &str) -> Result<Vec<(String, Vec<ViewColumn>)>, Error> {
+        parse_views(config.as_bytes())
+    }
+
+    #[test]
+    fn alignments() {
+        let parsed = parse_columns("index, name:right,current , seconds-left:left").unwrap();
+        assert_eq!(columns(&parsed), [(Column::Index, Alignment::Right), (Column::Name, Alignment::Right),
+                                      (Column::Current, Alignment::Middle), (Column::SecondsLeft, Alignment::Left)]);
+    }
+
+    #[test]
+    fn wrong_columns() {
+        assert!(parse_columns("name,code").unwrap_err().starts_with("unknown column \"code\""));
+        assert!(parse_columns("name:top").unwrap_err().starts_with("unknown alignment \"top\""));
+        assert_eq!(parse_columns(" , ").unwrap_err(), "no columns");
+        assert_eq!(parse_columns("").unwrap_err(), "no columns");
+    }
+
+    #[test]
+    fn config_views() {
+        let parsed = views("# columns for the phone\n\nview.short = name,current\n  \nview.ids=id,name\n").unwrap();
+        assert_eq!(parsed.iter().map(|v| &v.0[..]).collect::<Vec<_>>(), ["short", "ids"]);
+        assert_eq!(columns(&parsed[1].1), [(Column::Id, Alignment::Left), (Column::Name, Alignment::Left)]);
+    }
+
+    #[test]
+    fn wrong_config_line() {
+        let line = |config: &str| match views(config) {
+            Err(Error::WrongConfig(line, _)) => line,
+            other => panic!("expected WrongConfig, got {:?}", other.map(|v| v.len()))
+        };
+        assert_eq!(line("view.a = name\n# comment\n\nview.b = name,bogus\n"), 4);
+        assert_eq!(line("view.a = name\ncolumns = name\n"), 2);
+        assert_eq!(line("view.a name\n"), 1);
+        assert_eq!(line("view. = name\n"), 1);
+    }
+
+    #[test]
+    fn last_duplicate_wins() {
+        let parsed = views("view.default = name\nview.default = id,current\n");
+        let selected = select_view(parsed, None).unwrap().unwrap();
+        assert_eq!(columns(&selected), [(Column::Id, Alignment::Left), (Column::Current, Alignment::Middle)]);
+    }
+
+    #[test]
+    fn missing_view() {
+        assert!(select_view(views("view.short = name\n"), None).unwrap().is_none());
+        match select_view(views("view.short = name\n"), Some("long")) {
+            Err(Error::ViewNotFound(name)) => assert_eq!(name, "long"),
+            other => panic!("expected ViewNotFound, got {:?}", other.map(|v| v.is_some()))
+        }
+    }
+
+    #[test]
+    fn broken_config() {
+        // only the view asked for by name fails
+        assert!(select_view(views("view.default = nope\n"), None).unwrap().is_none());
+        assert!(select_view(views("view.default = nope\n"), Some("default")).is_err());
+    }
+}